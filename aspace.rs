@@ -1,17 +1,18 @@
+use core::cmp::min;
+use core::intrinsics::copy_nonoverlapping;
+
 use alloc;
 
-use con;
-use con::write;
 use cpu;
 use dict::*;
 use dlist::*;
+use log;
 use mem::heap_copy;
 use start32;
 use util::abort;
+use x86;
 pub use self::mapflag::MapFlag;
 
-static log_add_pte : bool = false;
-
 pub mod mapflag {
     pub type MapFlag = u8;
 
@@ -287,6 +288,34 @@ fn paddr_for_vpaddr<T>(vpaddr: *mut T) -> u64 {
     return vpaddr as u64 - start32::kernel_base;
 }
 
+// The 1GB below kernel_base, for physical memory outside the kernel's own
+// mapping (e.g. a framebuffer). Shared by all address spaces through
+// kernel_pdp.
+const kernel_window : u64 = 0xffffffff80000000;
+
+// Make paddr..paddr + size accessible to the kernel and return a pointer to
+// it. Memory in the first GB is already mapped, anything else goes into the
+// kernel window (with 2MB pages), which can only hold one range.
+pub fn map_kernel_phys(paddr : u64, size : u64) -> *mut u8 {
+    if paddr + size <= start32::MemoryEnd() {
+        return start32::MutPhysAddr(paddr);
+    }
+    let base = paddr & !0x1fffff;
+    let end = (paddr + size + 0x1fffff) & !0x1fffff;
+    if end - base > 1 << 30 {
+        abort("map_kernel_phys: range too large");
+    }
+    let pdp : *mut PageTable = start32::MutPhysAddr(start32::kernel_pdp_addr());
+    let pd = get_alloc_pt(pdp, kernel_window >> 30, 3);
+    let mut addr = base;
+    while addr < end {
+        // Present, writable, 2MB page
+        unsafe { (*pd)[((addr - base) >> 21) as usize] = addr | 0x83; }
+        addr += 1 << 21;
+    }
+    (kernel_window + (paddr - base)) as *mut u8
+}
+
 impl AddressSpace {
     fn init(&mut self) {
         self.pml4 = alloc_pml4();
@@ -308,6 +337,14 @@ impl AddressSpace {
         return self.mapcards.find(vaddr);
     }
 
+    pub fn mapcards<'a>(&'a self) -> DictIter<'a, MapCard> {
+        self.mapcards.iter()
+    }
+
+    pub fn backings<'a>(&'a self) -> DictIter<'a, Backing> {
+        self.backings.iter()
+    }
+
     pub fn mapcard_find_def(&self, vaddr: u64) -> MapCard {
         match self.mapcards.find_const(vaddr) {
             Some(card) => *card,
@@ -324,6 +361,15 @@ impl AddressSpace {
         self.mapcard_set_(&MapCard::new(vaddr, handle, offset, access));
     }
 
+    // End the mapping before vaddr, unless another mapping already starts
+    // exactly at vaddr.
+    pub fn mapcard_end(&mut self, vaddr : u64) {
+        match self.mapcard_find(vaddr) {
+            Some(ref card) if card.vaddr() == vaddr => (),
+            _ => self.mapcard_set(vaddr, 0, 0, 0),
+        }
+    }
+
     fn mapcard_set_(&mut self, new : &MapCard) {
         match self.mapcard_find(new.vaddr()) {
             Some(card) => {
@@ -370,50 +416,155 @@ impl AddressSpace {
         &*self.backings.insert(b)
     }
 
-    pub fn find_add_backing<'a>(&mut self, vaddr : u64) -> &'a Backing {
+    // Find the backing for vaddr, adding one for anonymous and physical
+    // memory. None if there's no mapping with some access, or the page has to
+    // come from another process.
+    pub fn find_add_backing<'a>(&mut self, vaddr : u64) -> Option<&'a Backing> {
         use aspace::mapflag::*;
 
         match self.backings.find_const(vaddr | 0xfff) {
-            Some(ref back) if back.has_vaddr(vaddr) => { return &**back; },
+            Some(ref back) if back.has_vaddr(vaddr) => { return Some(&**back); },
             _ => ()
         }
 
         match self.mapcard_find(vaddr) {
-            Some(card) => {
-                if (card.flags() & RWX) == 0 {
-                    abort("No access!");
-                }
-                if card.handle == 0 {
-                    if (card.flags() & DMA) == Anon {
-                        return self.add_anon_backing(card, vaddr);
-                    } else if (card.flags() & Phys) != 0 {
-                        return self.add_phys_backing(card, vaddr);
-                    } else {
-                        abort("not anon or phys for handle==0");
-                    }
+            Some(card) if (card.flags() & RWX) != 0 && card.handle == 0 => {
+                if (card.flags() & DMA) == Anon {
+                    Some(self.add_anon_backing(card, vaddr))
+                } else if (card.flags() & Phys) != 0 {
+                    Some(self.add_phys_backing(card, vaddr))
                 } else {
-                    abort("user mappings unimplemented");
+                    None
                 }
             },
-            None => {
-                abort("No mapping found!");
+            // No mapping, no access, or a mapping from another process
+            _ => None,
+        }
+    }
+
+    // Allocate the backing for vaddr right away and return a kernel pointer to
+    // the page, so the kernel can fill it in before the process runs.
+    pub fn prefill_anon(&mut self, vaddr : u64) -> *mut u8 {
+        match self.find_add_backing(vaddr & !0xfff) {
+            Some(back) => start32::MutPhysAddr(back.paddr()),
+            None => abort("prefill_anon: not mapped"),
+        }
+    }
+
+    // Copy len bytes between the kernel and user memory at vaddr. f gets a
+    // kernel pointer, the offset into the copy and the length for each part
+    // that is inside one page. Pages are faulted in as needed. Returns false
+    // (maybe after copying some) if part of the range isn't mapped with the
+    // given access, or is memory the kernel can't reach or fault in itself.
+    fn copy_user<F : FnMut(*mut u8, usize, usize)>(&mut self, vaddr : u64, len : usize, access : MapFlag, mut f : F) -> bool {
+        match vaddr.checked_add(len as u64) {
+            Some(end) if end <= 1 << 47 => (),
+            _ => return false,
+        }
+        let mut done = 0;
+        while done < len {
+            let addr = vaddr + done as u64;
+            let n = min(len - done, 4096 - (addr & 0xfff) as usize);
+            let paddr = match self.find_add_backing(addr & !0xfff) {
+                Some(back) if (back.flags() & access) == access => back.paddr(),
+                _ => return false,
+            };
+            // Only the kernel's direct mapping of low memory is usable here
+            if paddr >= 0u64.wrapping_sub(start32::kernel_base) {
+                return false;
+            }
+            f(start32::MutPhysAddr(paddr + (addr & 0xfff)), done, n);
+            done += n;
+        }
+        true
+    }
+
+    pub fn copy_from_user(&mut self, vaddr : u64, dst : &mut [u8]) -> bool {
+        let p = dst.as_mut_ptr();
+        self.copy_user(vaddr, dst.len(), mapflag::R, |user, offset, n| unsafe {
+            copy_nonoverlapping(user as *const u8, p.offset(offset as isize), n);
+        })
+    }
+
+    pub fn copy_to_user(&mut self, vaddr : u64, src : &[u8]) -> bool {
+        let p = src.as_ptr();
+        self.copy_user(vaddr, src.len(), mapflag::W, |user, offset, n| unsafe {
+            copy_nonoverlapping(p.offset(offset as isize), user, n);
+        })
+    }
+
+    // Make the page at vaddr safe for the kernel to write on a debugger's
+    // behalf. A read-only page mapped straight from physical memory (program
+    // text from a module, the boot info, the klog pages) gets replaced by a
+    // private anonymous copy. False for read-only pages that other processes
+    // can see, from another process or shared out to one.
+    fn make_private(&mut self, vaddr : u64) -> bool {
+        use aspace::mapflag::*;
+
+        let (flags, paddr) = match self.find_add_backing(vaddr) {
+            Some(back) => (back.flags(), back.paddr()),
+            None => return false,
+        };
+        if (flags & W) != 0 {
+            // The process can write it itself
+            return true;
+        }
+        if (flags & Phys) == 0 {
+            return false;
+        }
+        if (flags & DMA) != Phys {
+            return match self.sharings.find_const(vaddr) {
+                Some(s) if s.as_node.key == vaddr => false,
+                _ => true,
+            };
+        }
+        if paddr >= start32::MemoryEnd() {
+            return false;
+        }
+        self.backings.remove(vaddr | flags as u64);
+        let b = self.backings.insert(Backing::new_anon(vaddr, flags | Anon));
+        unsafe {
+            copy_nonoverlapping(start32::PhysAddr::<u8>(paddr), start32::MutPhysAddr(b.paddr()), 4096);
+        }
+        self.add_pte(vaddr, b.pte());
+        if x86::cr3() == self.cr3() {
+            unsafe { x86::invlpg(vaddr); }
+        }
+        true
+    }
+
+    // Like copy_to_user, but also writes read-only memory, for a debugger
+    // setting breakpoints in program text. See make_private.
+    pub fn debug_write(&mut self, vaddr : u64, src : &[u8]) -> bool {
+        let end = match vaddr.checked_add(src.len() as u64) {
+            Some(end) if end <= 1 << 47 => end,
+            _ => return false,
+        };
+        let mut page = vaddr & !0xfff;
+        while page < end {
+            if !self.make_private(page) {
+                return false;
             }
+            page += 4096;
         }
+        let p = src.as_ptr();
+        self.copy_user(vaddr, src.len(), 0, |user, offset, n| unsafe {
+            copy_nonoverlapping(p.offset(offset as isize), user, n);
+        })
     }
 
     pub fn share_backing<'a>(&mut self, vaddr: u64) -> &'a mut Sharing {
-        let back = self.find_add_backing(vaddr);
+        let back = match self.find_add_backing(vaddr) {
+            Some(back) => back,
+            None => abort("share_backing: not mapped"),
+        };
         let s = Sharing::new(self, back);
         self.sharings.insert(s)
     }
 
     pub fn add_pte(&mut self, vaddr : u64, pte : u64) {
-        if log_add_pte {
-            write("Mapping ");
-            con::writePHex(vaddr);
-            write(" to ");
-            con::writePHex(pte);
-            con::newline();
+        if log::on(log::ADD_PTE) {
+            kprintln!("Mapping {:#x} to {:#x}", vaddr, pte);
         }
         let pdp = get_alloc_pt(self.pml4, vaddr >> 39, 7);
         let pd = get_alloc_pt(pdp, vaddr >> 30, 7);
@@ -0,0 +1,79 @@
+// Backtraces for crash dumps (see crash.rs). The kernel is built with frame
+// pointers, so the stack is a chain of saved rbp values, each followed by a
+// return address. Addresses are looked up in a symbol table made from a first
+// link of the kernel (see mksyms.sh) and linked into the second.
+use core::slice;
+
+use con::Bytes;
+use start32::kernel_base;
+use util::cstr;
+
+const max_frames : usize = 32;
+
+// The table: number of symbols, their addresses in ascending order, offsets of
+// their names and then the NUL-terminated names.
+extern {
+    static ksyms : u64;
+}
+
+// Name and offset of the function containing addr.
+pub fn lookup(addr : u64) -> Option<(&'static [u8], u64)> {
+    let (addrs, names, strings) = unsafe {
+        let count = ksyms as usize;
+        let addrs = (&ksyms as *const u64).offset(1);
+        let names = addrs.offset(count as isize) as *const u32;
+        (slice::from_raw_parts(addrs, count),
+         slice::from_raw_parts(names, count),
+         names.offset(count as isize) as *const u8)
+    };
+    let i = match addrs.binary_search(&addr) {
+        Ok(i) => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+    let name = cstr(unsafe { strings.offset(names[i] as isize) });
+    Some((name, addr - addrs[i]))
+}
+
+fn print_frame(i : usize, addr : u64) {
+    match lookup(addr) {
+        Some((name, offset)) => kprintln!("  #{:<2} {:#018x} {}+{:#x}", i, addr, Bytes(name), offset),
+        None => kprintln!("  #{:<2} {:#018x}", i, addr),
+    }
+}
+
+// Stop at anything that doesn't look like a kernel stack frame, e.g. the user
+// rbp that's still there when we came from a syscall.
+fn valid_frame(rbp : u64) -> bool {
+    rbp >= kernel_base && rbp < 0u64.wrapping_sub(16) && rbp & 7 == 0
+}
+
+// Print the backtrace starting at rip, with rbp pointing at the frame of the
+// function's caller.
+pub fn backtrace(rip : u64, mut rbp : u64) {
+    kprintln!("backtrace:");
+    print_frame(0, rip);
+    for i in 1..max_frames {
+        let (next, ret) = match frame(rbp) {
+            Some(f) => f,
+            None => break,
+        };
+        if ret < kernel_base {
+            break;
+        }
+        print_frame(i, ret);
+        // Frames further up the stack are at higher addresses
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+// The saved rbp and return address in the frame at rbp, if it looks valid.
+pub fn frame(rbp : u64) -> Option<(u64, u64)> {
+    if !valid_frame(rbp) {
+        return None;
+    }
+    Some(unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) })
+}
@@ -0,0 +1,364 @@
+// Boot information in a protocol-independent form, filled in at startup from
+// either the multiboot 1 info struct or the multiboot 2 tag list. This happens
+// before the frame allocator is up, so everything is fixed-size.
+use core::mem::size_of;
+
+use con::Bytes;
+use mboot;
+use mboot2;
+use cmdline;
+use mem::Region;
+use start32::PhysAddr;
+use start32::PhysAddrRef;
+use start32::kernel_base;
+use util::abort;
+use util::cstr;
+
+pub use self::Protocol::*;
+
+pub const max_modules : usize = 32;
+pub const max_tables : usize = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Multiboot = 1,
+    Multiboot2 = 2,
+}
+
+#[derive(Clone, Copy)]
+pub struct Module {
+    pub start : u64,
+    pub end : u64,
+    // Physical address of the NUL-terminated command line, 0 if none.
+    pub string : u64,
+}
+
+const empty_module : Module = Module { start : 0, end : 0, string : 0 };
+
+impl Module {
+    pub fn cmdline(&self) -> &'static [u8] {
+        if self.string == 0 {
+            &[]
+        } else {
+            cstr(PhysAddr(self.string))
+        }
+    }
+
+    // The first word of the command line, used to refer to the module from
+    // the kernel command line.
+    pub fn name(&self) -> &'static [u8] {
+        match cmdline::words(self.cmdline()).next() {
+            Some(w) => w,
+            None => &[],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Framebuffer {
+    pub addr : u64,
+    pub pitch : u32,
+    pub width : u32,
+    pub height : u32,
+    pub bpp : u8,
+    // See mboot::FBType
+    pub fbtype : u8,
+    // Only valid for RGB framebuffers
+    pub format : mboot::FBPixelFormat,
+    // Only valid for indexed framebuffers: physical address of palette_count
+    // 3-byte RGB entries.
+    pub palette : u64,
+    pub palette_count : u16,
+}
+
+impl Framebuffer {
+    pub fn size(&self) -> u64 {
+        self.pitch as u64 * self.height as u64
+    }
+
+    fn from_multiboot(fb : &mboot::FB) -> Framebuffer {
+        let palette = fb.palette();
+        Framebuffer {
+            addr : fb.addr,
+            pitch : fb.pitch,
+            width : fb.width,
+            height : fb.height,
+            bpp : fb.bpp,
+            fbtype : fb.fbtype,
+            format : fb.pixel_format(),
+            palette : palette.addr as u64,
+            palette_count : palette.count,
+        }
+    }
+
+    fn from_multiboot2(tag : &mboot2::Tag) -> Framebuffer {
+        let fb : &mboot2::Framebuffer = unsafe { tag.data() };
+        let colors = tag.data_addr() + size_of::<mboot2::Framebuffer>() as u64;
+        let (format, count) = unsafe {
+            (*(colors as *const mboot::FBPixelFormat), *(colors as *const u16))
+        };
+        Framebuffer {
+            addr : fb.addr,
+            pitch : fb.pitch,
+            width : fb.width,
+            height : fb.height,
+            bpp : fb.bpp,
+            fbtype : fb.fbtype,
+            format : format,
+            // The palette entries follow the u16 count.
+            palette : colors + 2 - kernel_base,
+            palette_count : count,
+        }
+    }
+}
+
+// Physical address and layout of a memory map. Both protocols use the same
+// item types (mboot::MemoryTypes).
+#[derive(Clone, Copy)]
+pub struct MemoryMap {
+    addr : u64,
+    length : u64,
+    // Multiboot 2 has fixed-size entries while multiboot 1 has a size field
+    // before each item. 0 means multiboot 1.
+    entry_size : u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct MemoryRegion {
+    pub start : u64,
+    pub length : u64,
+    pub item_type : u32,
+}
+
+pub struct MemoryMapIter {
+    addr : *const u8,
+    end : *const u8,
+    entry_size : u64,
+}
+
+impl MemoryMap {
+    pub fn iter(&self) -> MemoryMapIter {
+        let addr : *const u8 = PhysAddr(self.addr);
+        MemoryMapIter {
+            addr : addr,
+            end : unsafe { addr.offset(self.length as isize) },
+            entry_size : self.entry_size,
+        }
+    }
+}
+
+impl Iterator for MemoryMapIter {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<MemoryRegion> {
+        if self.addr >= self.end {
+            return None;
+        }
+        unsafe {
+            if self.entry_size == 0 {
+                let item = *(self.addr as *const mboot::MemoryMapItem);
+                self.addr = self.addr.offset(4 + item.item_size as isize);
+                Some(MemoryRegion { start : item.start, length : item.length,
+                    item_type : item.item_type })
+            } else {
+                let item = *(self.addr as *const mboot2::MemoryMapEntry);
+                self.addr = self.addr.offset(self.entry_size as isize);
+                Some(MemoryRegion { start : item.start, length : item.length,
+                    item_type : item.item_type })
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub struct EFIMemoryMap {
+    pub addr : u64,
+    pub length : u64,
+    pub desc_size : u64,
+}
+
+pub struct Info {
+    pub protocol : Protocol,
+    // Physical address of the NUL-terminated kernel command line, 0 if none.
+    pub cmdline : u64,
+    // Lower and upper memory in kB, 0 if not provided.
+    pub mem_lower : u32,
+    pub mem_upper : u32,
+    pub mmap : Option<MemoryMap>,
+    pub efi_mmap : Option<EFIMemoryMap>,
+    pub fb : Option<Framebuffer>,
+    // Physical address of the ACPI RSDP (as copied by the boot loader), 0 if
+    // not provided. rsdp_v2 if it's the ACPI 2.0+ XSDP.
+    #[allow(dead_code)]
+    pub rsdp : u64,
+    #[allow(dead_code)]
+    pub rsdp_v2 : bool,
+
+    modules : [Module; max_modules],
+    num_modules : usize,
+    // Boot loader structures that have to be kept around: the info itself,
+    // the module list and the memory map.
+    tables : [Region; max_tables],
+    num_tables : usize,
+}
+
+static mut info : Info = Info {
+    protocol : Multiboot,
+    cmdline : 0,
+    mem_lower : 0,
+    mem_upper : 0,
+    mmap : None,
+    efi_mmap : None,
+    fb : None,
+    rsdp : 0,
+    rsdp_v2 : false,
+    modules : [empty_module; max_modules],
+    num_modules : 0,
+    tables : [::mem::empty_region; max_tables],
+    num_tables : 0,
+};
+
+pub fn get() -> &'static Info {
+    unsafe { &info }
+}
+
+// Parse the boot information at paddr, as handed to us by a boot loader using
+// the protocol identified by magic.
+pub fn init(magic : u32, paddr : u64) {
+    let i = unsafe { &mut info };
+    match magic {
+        mboot::Magic => i.from_multiboot(paddr),
+        mboot2::Magic => i.from_multiboot2(paddr),
+        _ => {
+            kerror!("Boot loader magic {:#x}", magic);
+            abort("Unknown boot loader magic");
+        }
+    }
+}
+
+impl Info {
+    pub fn modules(&self) -> &[Module] {
+        &self.modules[..self.num_modules]
+    }
+
+    pub fn tables(&self) -> &[Region] {
+        &self.tables[..self.num_tables]
+    }
+
+    pub fn print(&self) {
+        kprintln!("Multiboot {} info at {:#x}", self.protocol as u32, self.tables[0].start);
+        if self.cmdline != 0 {
+            kprintln!("Command line: \"{}\"", Bytes(cstr(PhysAddr(self.cmdline))));
+        }
+        if self.mem_upper != 0 {
+            kprintln!("{}kB lower memory, {}kB upper memory", self.mem_lower, self.mem_upper);
+        }
+        if self.mmap.is_none() {
+            kprintln!("No memory map");
+        }
+        kprintln!("{} modules", self.num_modules);
+        match self.fb {
+            Some(ref fb) => kprintln!("Framebuffer {}x{}x{} type {} at {:#x}",
+                fb.width, fb.height, fb.bpp, fb.fbtype, fb.addr),
+            None => (),
+        }
+        if self.rsdp != 0 {
+            kprintln!("ACPI RSDP at {:#x}", self.rsdp);
+        }
+    }
+
+    fn add_module(&mut self, start : u64, end : u64, string : u64) {
+        if self.num_modules == max_modules {
+            abort("Too many boot modules");
+        }
+        self.modules[self.num_modules] = Module { start : start, end : end, string : string };
+        self.num_modules += 1;
+    }
+
+    fn add_table(&mut self, start : u64, end : u64) {
+        if self.num_tables == max_tables {
+            abort("Too many boot tables");
+        }
+        self.tables[self.num_tables] = Region::new(start, end);
+        self.num_tables += 1;
+    }
+
+    fn from_multiboot(&mut self, paddr : u64) {
+        let mbi : &mboot::Info = PhysAddrRef(paddr);
+        self.protocol = Multiboot;
+        self.add_table(paddr, paddr + size_of::<mboot::Info>() as u64);
+        if mbi.has(mboot::CommandLine) {
+            self.cmdline = mbi.cmdline as u64;
+        }
+        if mbi.has(mboot::MemorySize) {
+            self.mem_lower = mbi.mem_lower;
+            self.mem_upper = mbi.mem_upper;
+        }
+        if mbi.has(mboot::MemoryMap) {
+            let addr = mbi.mmap_addr as u64;
+            let length = mbi.mmap_length as u64;
+            self.mmap = Some(MemoryMap { addr : addr, length : length, entry_size : 0 });
+            self.add_table(addr, addr + length);
+        }
+        if mbi.has(mboot::Modules) {
+            let addr = mbi.mods_addr as u64;
+            let size = size_of::<mboot::Module>() as u64;
+            self.add_table(addr, addr + mbi.mods_count as u64 * size);
+            for m in mbi.modules(PhysAddr).iter() {
+                self.add_module(m.start as u64, m.end as u64, m.string as u64);
+            }
+        }
+        if mbi.has(mboot::Framebuffer) {
+            self.fb = Some(Framebuffer::from_multiboot(&mbi.fb));
+        }
+    }
+
+    fn from_multiboot2(&mut self, paddr : u64) {
+        use mboot2::*;
+
+        let header : &InfoHeader = PhysAddrRef(paddr);
+        self.protocol = Multiboot2;
+        // Everything except the memory map is inside the tags, so this is the
+        // only table.
+        self.add_table(paddr, paddr + header.total_size as u64);
+        for tag in tags(PhysAddr::<u8>(paddr) as u64) {
+            // Physical address of the tag data
+            let data = tag.data_addr() - kernel_base;
+            if tag.is(CommandLine) {
+                self.cmdline = data;
+            } else if tag.is(BasicMemInfo) {
+                let mem : &BasicMemInfo = unsafe { tag.data() };
+                self.mem_lower = mem.mem_lower;
+                self.mem_upper = mem.mem_upper;
+            } else if tag.is(TagType::Module) {
+                let m : &mboot2::Module = unsafe { tag.data() };
+                let string = data + size_of::<mboot2::Module>() as u64;
+                self.add_module(m.start as u64, m.end as u64, string);
+            } else if tag.is(TagType::MemoryMap) {
+                let mmap : &mboot2::MemoryMap = unsafe { tag.data() };
+                let header_size = size_of::<mboot2::MemoryMap>() as u64;
+                self.mmap = Some(::boot::MemoryMap {
+                    addr : data + header_size,
+                    length : tag.data_size() - header_size,
+                    entry_size : mmap.entry_size as u64,
+                });
+            } else if tag.is(TagType::Framebuffer) {
+                self.fb = Some(::boot::Framebuffer::from_multiboot2(tag));
+            } else if tag.is(ACPINew) || (tag.is(ACPIOld) && self.rsdp == 0) {
+                // The tag contains a copy of the RSDP. Prefer the new one if
+                // we get both.
+                self.rsdp = data;
+                self.rsdp_v2 = tag.is(ACPINew);
+            } else if tag.is(EFIMemoryMap) {
+                let mmap : &mboot2::EFIMemoryMap = unsafe { tag.data() };
+                let header_size = size_of::<mboot2::EFIMemoryMap>() as u64;
+                self.efi_mmap = Some(::boot::EFIMemoryMap {
+                    addr : data + header_size,
+                    length : tag.data_size() - header_size,
+                    desc_size : mmap.desc_size as u64,
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+// The boot information page: a read-only copy of boot::Info for user space,
+// mapped at a fixed address in the init process and available to other
+// processes through the BOOTINFO syscall.
+//
+// The page starts with a Header. Variable-size data (strings and tables) is
+// referred to by byte offsets from the start of the page. If everything
+// doesn't fit, the Truncated flag is set and the tables are cut short.
+use core::cmp::min;
+use core::intrinsics::copy_nonoverlapping;
+use core::mem::size_of;
+
+use aspace::AddressSpace;
+use alloc;
+use boot;
+use start32::PhysAddr;
+use start32::kernel_base;
+
+// Where the page is mapped in the init process.
+pub const vaddr : u64 = 0xfe000;
+
+pub const Magic : u32 = 0x544f4f42; // "BOOT"
+// Incremented when fields are changed or removed, adding fields at the end
+// of the header or new flags doesn't change the version.
+pub const Version : u32 = 1;
+
+pub mod flag {
+    pub const MemoryMap : u32 = 1;
+    pub const Framebuffer : u32 = 2;
+    pub const RSDP : u32 = 4;
+    pub const RSDPv2 : u32 = 8;
+    pub const Truncated : u32 = 16;
+}
+
+#[repr(C)]
+pub struct Header {
+    pub magic : u32,
+    pub version : u32,
+    // Bytes of the page used
+    pub size : u32,
+    pub flags : u32,
+    // 1 for multiboot, 2 for multiboot 2
+    pub protocol : u32,
+    // Kernel command line (not NUL-terminated)
+    pub cmdline : u32,
+    pub cmdline_len : u32,
+    // Lower and upper memory in kB, 0 if not provided
+    pub mem_lower : u32,
+    pub mem_upper : u32,
+    // MemoryEntry table
+    pub mmap : u32,
+    pub mmap_count : u32,
+    // ModuleEntry table
+    pub modules : u32,
+    pub module_count : u32,
+    pub fb : FramebufferInfo,
+    pub rsdp : u64,
+}
+
+#[repr(C)]
+pub struct MemoryEntry {
+    pub start : u64,
+    pub length : u64,
+    // See mboot::MemoryTypes
+    pub item_type : u32,
+    pub reserved : u32,
+}
+
+#[repr(C)]
+pub struct ModuleEntry {
+    pub start : u64,
+    pub end : u64,
+    pub cmdline : u32,
+    pub cmdline_len : u32,
+}
+
+#[repr(C)]
+pub struct FramebufferInfo {
+    pub addr : u64,
+    pub pitch : u32,
+    pub width : u32,
+    pub height : u32,
+    pub bpp : u8,
+    // See mboot::FBType
+    pub fbtype : u8,
+    // For RGB framebuffers: bit position and number of bits of each color
+    pub red_shift : u8,
+    pub red_bits : u8,
+    pub green_shift : u8,
+    pub green_bits : u8,
+    pub blue_shift : u8,
+    pub blue_bits : u8,
+    // For indexed framebuffers: palette of 3-byte RGB entries
+    pub palette : u32,
+    pub palette_count : u32,
+}
+
+struct Builder {
+    page : *mut u8,
+    pos : u32,
+    truncated : bool,
+}
+
+impl Builder {
+    fn header<'a>(&self) -> &'a mut Header {
+        unsafe { &mut *(self.page as *mut Header) }
+    }
+
+    // Reserve n bytes (8-byte aligned), or None if the page is full.
+    fn reserve(&mut self, n : usize) -> Option<u32> {
+        let start = (self.pos + 7) & !7;
+        if start as usize + n > 4096 {
+            self.truncated = true;
+            return None;
+        }
+        self.pos = start + n as u32;
+        Some(start)
+    }
+
+    fn at<'a, T>(&self, offset : u32) -> &'a mut T {
+        unsafe { &mut *(self.page.offset(offset as isize) as *mut T) }
+    }
+
+    fn bytes(&mut self, s : &[u8]) -> (u32, u32) {
+        match self.reserve(s.len()) {
+            Some(offset) => {
+                unsafe {
+                    copy_nonoverlapping(s.as_ptr(), self.page.offset(offset as isize), s.len());
+                }
+                (offset, s.len() as u32)
+            },
+            None => (0, 0),
+        }
+    }
+
+    // Reserve a table of up to n entries of T.
+    fn table<T>(&mut self, n : usize) -> (u32, usize) {
+        let n = min(n, (4096 - self.pos as usize) / size_of::<T>());
+        match self.reserve(n * size_of::<T>()) {
+            Some(offset) => (offset, n),
+            None => (0, 0),
+        }
+    }
+}
+
+fn build(info : &boot::Info) -> *mut u8 {
+    let mut b = Builder {
+        page : alloc::<[u8; 4096]>() as *mut [u8; 4096] as *mut u8,
+        pos : size_of::<Header>() as u32,
+        truncated : false,
+    };
+    let h = b.header();
+    h.magic = Magic;
+    h.version = Version;
+    h.protocol = info.protocol as u32;
+    h.mem_lower = info.mem_lower;
+    h.mem_upper = info.mem_upper;
+    if info.cmdline != 0 {
+        let (offset, len) = b.bytes(::util::cstr(PhysAddr(info.cmdline)));
+        h.cmdline = offset;
+        h.cmdline_len = len;
+    }
+
+    let modules = info.modules();
+    let (offset, n) = b.table::<ModuleEntry>(modules.len());
+    if n < modules.len() {
+        b.truncated = true;
+    }
+    h.modules = offset;
+    h.module_count = n as u32;
+    for (i, m) in modules[..n].iter().enumerate() {
+        let e : &mut ModuleEntry = b.at(offset + (i * size_of::<ModuleEntry>()) as u32);
+        e.start = m.start;
+        e.end = m.end;
+        let (s, len) = b.bytes(m.cmdline());
+        e.cmdline = s;
+        e.cmdline_len = len;
+    }
+
+    match info.fb {
+        Some(ref fb) => {
+            h.flags |= flag::Framebuffer;
+            let f = &mut h.fb;
+            f.addr = fb.addr;
+            f.pitch = fb.pitch;
+            f.width = fb.width;
+            f.height = fb.height;
+            f.bpp = fb.bpp;
+            f.fbtype = fb.fbtype;
+            f.red_shift = fb.format.red_shift;
+            f.red_bits = fb.format.red_mask;
+            f.green_shift = fb.format.green_shift;
+            f.green_bits = fb.format.green_mask;
+            f.blue_shift = fb.format.blue_shift;
+            f.blue_bits = fb.format.blue_mask;
+            if fb.palette_count != 0 {
+                let palette : &[u8] = unsafe {
+                    ::core::slice::from_raw_parts(PhysAddr(fb.palette), 3 * fb.palette_count as usize)
+                };
+                let (offset, len) = b.bytes(palette);
+                f.palette = offset;
+                f.palette_count = len / 3;
+            }
+        },
+        None => (),
+    }
+
+    if info.rsdp != 0 {
+        h.flags |= flag::RSDP;
+        if info.rsdp_v2 {
+            h.flags |= flag::RSDPv2;
+        }
+        h.rsdp = info.rsdp;
+    }
+
+    // Last, since it's the most likely to be big.
+    match info.mmap {
+        Some(ref mmap) => {
+            h.flags |= flag::MemoryMap;
+            let count = mmap.iter().count();
+            let (offset, n) = b.table::<MemoryEntry>(count);
+            if n < count {
+                b.truncated = true;
+            }
+            h.mmap = offset;
+            h.mmap_count = n as u32;
+            for (i, r) in mmap.iter().take(n).enumerate() {
+                let e : &mut MemoryEntry = b.at(offset + (i * size_of::<MemoryEntry>()) as u32);
+                e.start = r.start;
+                e.length = r.length;
+                e.item_type = r.item_type;
+            }
+        },
+        None => (),
+    }
+
+    if b.truncated {
+        h.flags |= flag::Truncated;
+    }
+    h.size = b.pos;
+    b.page
+}
+
+static mut page : u64 = 0;
+
+// Physical address of the page, built on first use.
+pub fn paddr() -> u64 {
+    unsafe {
+        if page == 0 {
+            page = build(boot::get()) as u64 - kernel_base;
+        }
+        page
+    }
+}
+
+// Map the page read-only at addr, which must be page aligned and not mapped
+// yet. Returns false if it isn't.
+pub fn map(aspace : &mut AddressSpace, addr : u64) -> bool {
+    use aspace::mapflag::*;
+    if addr & 0xfff != 0 || addr == 0 || addr >= 1 << 47 || aspace.mapcard_find_def(addr).flags() != 0 {
+        return false;
+    }
+    aspace.mapcard_set(addr, 0, paddr().wrapping_sub(addr), Phys | R);
+    aspace.mapcard_end(addr + 0x1000);
+    true
+}
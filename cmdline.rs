@@ -0,0 +1,237 @@
+// Kernel command line options, e.g.
+// "log=ipc,pfault memtest memstat=off mem=64M irqproc=fs init=init
+// serial=com2,9600 gdb=com1 test=all stats=cycles,line events=16".
+use con::Bytes;
+use events;
+use log;
+use serial;
+use start32::PhysAddr;
+use util::cstr;
+
+pub struct Options {
+    // Run the frame allocator self-test at boot
+    pub mem_test : bool,
+    // Ignore physical memory from this address and up, 0 for no limit
+    pub mem_limit : u64,
+    // Name of the module to use as IRQ process, empty for the first module
+    pub irq_proc : &'static [u8],
+    // Program to start from the initrd (or module) that gets the initrd
+    pub init : &'static [u8],
+    // Serial port for kernel output and the monitor, 0 for none
+    pub serial_port : u16,
+    pub serial_baud : u32,
+    // Show memory usage on the top line of the screen
+    pub mem_stat : bool,
+    // Serial port for the GDB stub, 0 for none
+    pub gdb_port : u16,
+    // Comma-separated self-tests to run instead of the boot modules, see
+    // ktest.rs
+    pub tests : &'static [u8],
+    // Count cycles spent in syscalls and page faults, see stats.rs
+    pub stat_cycles : bool,
+    // Show the event counters on the memory status line
+    pub stat_line : bool,
+    // Pages of event trace buffer per CPU, 0 for none
+    pub event_pages : usize,
+}
+
+static mut options : Options = Options {
+    mem_test : false,
+    mem_limit : 0,
+    irq_proc : &[],
+    init : b"init",
+    serial_port : serial::COM1,
+    serial_baud : 115200,
+    mem_stat : true,
+    gdb_port : 0,
+    tests : &[],
+    stat_cycles : false,
+    stat_line : false,
+    event_pages : 0,
+};
+
+pub fn get() -> &'static Options {
+    unsafe { &options }
+}
+
+// Iterate the parts of a string separated by sep, skipping empty parts.
+pub struct Split<'a> {
+    rest : &'a [u8],
+    sep : u8,
+}
+
+pub fn split<'a>(s : &'a [u8], sep : u8) -> Split<'a> {
+    Split { rest : s, sep : sep }
+}
+
+pub fn words<'a>(s : &'a [u8]) -> Split<'a> {
+    split(s, b' ')
+}
+
+impl<'a> Iterator for Split<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        while self.rest.len() > 0 && self.rest[0] == self.sep {
+            self.rest = &self.rest[1..];
+        }
+        if self.rest.len() == 0 {
+            return None;
+        }
+        let mut i = 0;
+        while i < self.rest.len() && self.rest[i] != self.sep {
+            i += 1;
+        }
+        let res = &self.rest[..i];
+        self.rest = &self.rest[i..];
+        Some(res)
+    }
+}
+
+// Split "key=value" at the first '='. Without a '=', the value is empty.
+pub fn key_value<'a>(word : &'a [u8]) -> (&'a [u8], &'a [u8]) {
+    for i in 0..word.len() {
+        if word[i] == b'=' {
+            return (&word[..i], &word[i + 1..]);
+        }
+    }
+    (word, &[])
+}
+
+// Decimal, or hexadecimal with a 0x prefix.
+pub fn parse_uint(s : &[u8]) -> Option<u64> {
+    let (digits, base) = if s.len() > 2 && s[0] == b'0' && s[1] == b'x' {
+        (&s[2..], 16)
+    } else {
+        (s, 10)
+    };
+    if digits.len() == 0 {
+        return None;
+    }
+    let mut res : u64 = 0;
+    for &c in digits {
+        let d = match c {
+            b'0' ..= b'9' => c - b'0',
+            b'a' ..= b'f' => c - b'a' + 10,
+            b'A' ..= b'F' => c - b'A' + 10,
+            _ => return None,
+        } as u64;
+        if d >= base {
+            return None;
+        }
+        res = res * base + d;
+    }
+    Some(res)
+}
+
+// A number with an optional K, M or G suffix.
+pub fn parse_size(s : &[u8]) -> Option<u64> {
+    if s.len() == 0 {
+        return None;
+    }
+    let shift = match s[s.len() - 1] {
+        b'K' | b'k' => 10,
+        b'M' | b'm' => 20,
+        b'G' | b'g' => 30,
+        _ => 0,
+    };
+    let digits = if shift == 0 { s } else { &s[..s.len() - 1] };
+    match parse_uint(digits) {
+        Some(n) => Some(n << shift),
+        None => None,
+    }
+}
+
+fn bad_option(what : &str, word : &[u8]) {
+    kwarn!("cmdline: {} \"{}\"", what, Bytes(word));
+}
+
+// A serial port: com1, com2, off (0) or an I/O port number.
+fn parse_port(s : &[u8]) -> Option<u16> {
+    match s {
+        b"off" => Some(0),
+        b"com1" => Some(serial::COM1),
+        b"com2" => Some(serial::COM2),
+        _ => parse_uint(s).map(|p| p as u16),
+    }
+}
+
+// Parse the NUL-terminated command line at paddr (0 for no command line).
+// Words without a '=' that we don't recognize are ignored, since the boot
+// loader usually includes the kernel's file name.
+pub fn parse(paddr : u64) {
+    if paddr == 0 {
+        return;
+    }
+    let o = unsafe { &mut options };
+    for word in words(cstr(PhysAddr(paddr))) {
+        let (key, value) = key_value(word);
+        if key == b"log" {
+            for name in split(value, b',') {
+                let m = log::by_name(name);
+                if m == 0 {
+                    bad_option("unknown log category", name);
+                }
+                log::set(log::get() | m);
+            }
+        } else if key == b"loglevel" {
+            match log::level_by_name(value).or_else(|| parse_uint(value).map(|l| l as log::Level)) {
+                Some(l) => log::set_level(l),
+                None => bad_option("unknown log level", word),
+            }
+        } else if key == b"memtest" {
+            o.mem_test = true;
+        } else if key == b"memstat" {
+            match value {
+                b"" | b"on" => o.mem_stat = true,
+                b"off" => o.mem_stat = false,
+                _ => bad_option("bad memstat value", word),
+            }
+        } else if key == b"mem" {
+            match parse_size(value) {
+                Some(n) => o.mem_limit = n,
+                None => bad_option("bad memory size", word),
+            }
+        } else if key == b"irqproc" {
+            o.irq_proc = value;
+        } else if key == b"init" {
+            o.init = value;
+        } else if key == b"serial" {
+            let mut parts = split(value, b',');
+            match parts.next().and_then(parse_port) {
+                Some(p) => o.serial_port = p,
+                None => bad_option("bad serial port", word),
+            }
+            match parts.next().map(parse_uint) {
+                Some(Some(baud)) => o.serial_baud = baud as u32,
+                Some(None) => bad_option("bad baud rate", word),
+                None => (),
+            }
+        } else if key == b"test" {
+            o.tests = value;
+        } else if key == b"stats" {
+            for name in split(value, b',') {
+                match name {
+                    b"cycles" => o.stat_cycles = true,
+                    b"line" => {
+                        o.stat_line = true;
+                        o.mem_stat = true;
+                    },
+                    _ => bad_option("unknown stats option", name),
+                }
+            }
+        } else if key == b"events" {
+            match parse_uint(value) {
+                Some(n) if n as usize <= events::max_pages => o.event_pages = n as usize,
+                _ => bad_option("bad number of event pages", word),
+            }
+        } else if key == b"gdb" {
+            match parse_port(value) {
+                Some(p) => o.gdb_port = p,
+                None => bad_option("bad gdb port", word),
+            }
+        } else if value.len() > 0 {
+            bad_option("unknown option", word);
+        }
+    }
+}
@@ -1,10 +1,18 @@
-use core::cmp::min;
+use core::cmp::{max, min};
+use core::fmt;
+use core::fmt::Write;
+
+use fbcon::FbCon;
+use klog;
+use serial;
+use start32::kernel_base;
+use x86::outb;
 
 // NOTE: We cheat here - we know the memcpy in runtime.s copies from the
 // beginning so we use this on overlapping ranges too.
 // (To avoid having to implement memmove.)
 #[inline]
-unsafe fn copy_memory<T>(dst: *mut T, src: *const T, count: usize) {
+pub unsafe fn copy_memory<T>(dst: *mut T, src: *const T, count: usize) {
     use core::intrinsics::copy_nonoverlapping;
     copy_nonoverlapping(src, dst, count);
 }
@@ -31,72 +39,12 @@ pub trait Writer {
         }
     }
 
-    fn writeInt(&mut self, x : isize) {
-        self.writeSigned(0, false, x);
-    }
-
-    fn writeUInt(&mut self, x : usize) {
-        self.writeUnsigned(0, false, 10, false, x);
-    }
-
-    fn writeHex(&mut self, x : usize) {
-        self.writeUnsigned(0, false, 16, true, x);
-    }
-
-    fn writePtr<T>(&mut self, x : *const T) {
-        self.writePHex(x as usize);
-    }
-    fn writeMutPtr<T>(&mut self, x : *mut T) {
-        self.writePHex(x as usize);
-    }
-    #[inline(never)]
-    fn writePHex(&mut self, x : usize) {
-        self.writeUnsigned(16, true, 16, true, x);
-    }
-
-    #[cfg(no_console)]
-    fn writeUnsigned(&mut self, width : usize, leading_zero : bool, base : usize, show_base : bool, num : usize) {
-        // nothing
-    }
-
-    #[cfg(not(no_console))]
-    fn writeUnsigned(&mut self, width : usize, leading_zero : bool, base : usize, show_base : bool, num : usize)
-    {
-        if show_base && base == 16 {
-            self.write("0x");
-        }
-        let mut buf : [u8; 32] = [0; 32]; // TODO redundant type annotation?
-        let mut len = 0;
-        let mut num_ = num;
-        loop {
-            let i = num_ % base;
-            buf[len] = "0123456789abcdef".as_bytes()[i];
-            len += 1;
-            num_ /= base;
-            if num_ == 0 { break; }
-        }
-        if width > 0 {
-            let c = if leading_zero { '0' } else { ' ' };
-            for _ in 0..min(width - len, width) {
-                self.putc(c);
-            }
-        }
-        while len > 0 {
-            len -= 1;
-            self.putc(buf[len] as char);
+    fn writeBytes(&mut self, bytes : &[u8]) {
+        for b in bytes {
+            self.putc(*b as char);
         }
     }
 
-    fn writeSigned(&mut self, width : usize, leading_zero : bool, num : isize) {
-        let abs = if num < 0 {
-            self.putc('-');
-            -num
-        } else {
-            num
-        } as usize;
-        self.writeUnsigned(width, leading_zero, 10, false, abs);
-    }
-
     #[inline(never)]
     fn writeCStr(&mut self, c_str : *const u8) {
         unsafe {
@@ -117,17 +65,97 @@ impl Writer for DebugCon {
     }
 }
 
-static mut con : Console = Console { buffer : 0 as *mut u16, position : 0, color : 0, debug : true };
+impl fmt::Write for DebugCon {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        self.write(s);
+        Ok(())
+    }
+}
+
+// Displays a byte string, e.g. a name from a module command line.
+pub struct Bytes<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Display for Bytes<'a> {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        for &b in self.0 {
+            try!(f.write_char(b as char));
+        }
+        Ok(())
+    }
+}
+
+// Where the escape sequence parser is.
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    // After ESC
+    Esc,
+    // After ESC [, reading parameters
+    Csi,
+}
+
+const max_params : usize = 4;
+
+// Colour attribute after reset.
+const default_color : u16 = 0x0f00;
+// VGA colour numbers for the ANSI colours black, red, green, yellow, blue,
+// magenta, cyan and white.
+const ansi_colors : [u16; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+// CRTC registers for the hardware cursor
+const crtc_index : u16 = 0x3d4;
+const crtc_data : u16 = 0x3d5;
+const crtc_cursor_high : u8 = 0x0e;
+const crtc_cursor_low : u8 = 0x0f;
+
+static mut con : Console = Console {
+    buffer : 0 as *mut u16, position : 0, color : 0, debug : true, fb : 0 as *mut FbCon,
+    width : 0, height : 0, hw_cursor : false, screen_offset : 0, cursor : !0,
+    escape : Escape::None, params : [0; max_params], num_params : 0, saved : 0,
+};
 
 pub struct Console {
     buffer : *mut u16,
     position : usize,
     pub color : u16,
     pub debug : bool,
+    // Framebuffer console to use instead of the text buffer, if not null
+    fb : *mut FbCon,
+    width : usize,
+    height : usize,
+    // Whether to move the hardware cursor, and the position of buffer from
+    // the start of the screen.
+    hw_cursor : bool,
+    screen_offset : usize,
+    // Last hardware cursor position set
+    cursor : usize,
+    escape : Escape,
+    params : [usize; max_params],
+    num_params : usize,
+    // Position saved with ESC [ s
+    saved : usize,
 }
 
-pub fn init(buffer : *mut u16) {
-    unsafe { con = Console::new(buffer); }
+// The full text screen, with the hardware cursor following the output.
+pub fn init(buffer : *mut u16, width : usize, height : usize) {
+    unsafe {
+        con = Console::new(buffer, width, height);
+        con.hw_cursor = true;
+    }
+}
+
+// Text screen size from the BIOS data area, or 80x25 if it doesn't look
+// right (e.g. without a BIOS).
+pub fn bios_geometry() -> (usize, usize) {
+    let (cols, rows) = unsafe {
+        (*((kernel_base + 0x44a) as *const u16) as usize,
+         *((kernel_base + 0x484) as *const u8) as usize + 1)
+    };
+    if cols < 40 || cols > 256 || rows < 25 || rows > 128 {
+        (80, 25)
+    } else {
+        (cols, rows)
+    }
 }
 
 pub fn get() -> &'static mut Console {
@@ -139,15 +167,49 @@ pub fn dbg() -> DebugCon {
 }
 
 impl Console {
-    pub fn new(buffer : *mut u16) -> Console {
+    pub fn new(buffer : *mut u16, width : usize, height : usize) -> Console {
         Console {
             buffer : buffer,
             position : 0,
-            color : 0x0f00,
+            color : default_color,
             debug : true,
+            fb : 0 as *mut FbCon,
+            width : width,
+            height : height,
+            hw_cursor : false,
+            screen_offset : 0,
+            cursor : !0,
+            escape : Escape::None,
+            params : [0; max_params],
+            num_params : 0,
+            saved : 0,
         }
     }
 
+    pub fn set_fb(&mut self, fb : *mut FbCon) {
+        self.fb = fb;
+    }
+
+    // Change the screen size, e.g. to what the boot loader says rather than
+    // the BIOS. The buffer stays where it is.
+    pub fn set_geometry(&mut self, width : usize, height : usize) {
+        self.width = width;
+        self.height = height;
+        if self.position >= width * height {
+            self.position = width * (height - 1);
+        }
+    }
+
+    // Leave the top n lines of the screen to someone else.
+    pub fn reserve_top(&mut self, n : usize) {
+        let cells = n * self.width;
+        unsafe { self.buffer = self.buffer.offset(cells as isize); }
+        self.height -= n;
+        self.screen_offset += cells;
+        self.position = if self.position >= cells { self.position - cells } else { 0 };
+        self.saved = 0;
+    }
+
     pub fn putchar(&self, position : usize, c : u16) {
         unsafe {
             *self.buffer.offset(position as isize) = c;
@@ -155,14 +217,17 @@ impl Console {
     }
 
     pub fn clear(&mut self) {
-        for i in 0..(80*24) {
-            self.putchar(i, 0);
+        if !self.fb.is_null() {
+            unsafe { (*self.fb).clear(); }
+            return;
         }
+        let n = self.width * self.height;
+        self.clear_range(0, n);
         self.position = 0;
     }
 
-    fn width(&self) -> usize { 80 }
-    fn height(&self) -> usize { 24 }
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
 
     fn clear_eol(&mut self) {
         let count = self.width() - (self.position % self.width());
@@ -193,13 +258,149 @@ impl Console {
         self.clear_range(count, dist);
         self.position = count;
     }
+
+    // Move the hardware cursor to the output position, if it's ours.
+    pub fn sync_cursor(&mut self) {
+        let pos = self.screen_offset + self.position;
+        if !self.hw_cursor || !self.fb.is_null() || pos == self.cursor {
+            return;
+        }
+        self.cursor = pos;
+        unsafe {
+            outb(crtc_index, crtc_cursor_low);
+            outb(crtc_data, pos as u8);
+            outb(crtc_index, crtc_cursor_high);
+            outb(crtc_data, (pos >> 8) as u8);
+        }
+    }
+
+    fn tab(&mut self) {
+        let col = self.position % self.width;
+        let n = min((col + 8) & !7, self.width) - col;
+        self.clear_range(self.position, n);
+        self.position += n;
+    }
+
+    // Feed c to the escape sequence parser. Returns false if c isn't part of
+    // an escape sequence. Handled: ESC [ ... m (30-37, 40-47, 90-97, bold,
+    // reverse), ESC [ n A/B/C/D, ESC [ row;col H, ESC [ n J/K and ESC [ s/u.
+    fn escape(&mut self, c : char) -> bool {
+        match self.escape {
+            Escape::None => {
+                if c != '\x1b' {
+                    return false;
+                }
+                self.escape = Escape::Esc;
+            },
+            Escape::Esc => {
+                if c == '[' {
+                    self.escape = Escape::Csi;
+                    self.params = [0; max_params];
+                    self.num_params = 0;
+                } else {
+                    self.escape = Escape::None;
+                }
+            },
+            Escape::Csi => match c {
+                '0' ..= '9' => {
+                    if self.num_params == 0 {
+                        self.num_params = 1;
+                    }
+                    let p = &mut self.params[self.num_params - 1];
+                    *p = min(*p * 10 + (c as usize - '0' as usize), 9999);
+                },
+                ';' => {
+                    if self.num_params == 0 {
+                        self.num_params = 1;
+                    }
+                    if self.num_params < max_params {
+                        self.num_params += 1;
+                    }
+                },
+                // Private and intermediate characters, like the ? in ESC [ ? 25 l
+                ' ' ..= '?' => (),
+                '@' ..= '~' => {
+                    self.escape = Escape::None;
+                    // The framebuffer console just drops the sequences.
+                    if self.fb.is_null() {
+                        self.csi(c);
+                    }
+                },
+                // Anything else cancels the sequence
+                _ => self.escape = Escape::None,
+            },
+        }
+        true
+    }
+
+    // Parameter i, or default if it's missing or 0.
+    fn param(&self, i : usize, default : usize) -> usize {
+        if i < self.num_params && self.params[i] != 0 { self.params[i] } else { default }
+    }
+
+    fn csi(&mut self, c : char) {
+        let (w, h) = (self.width, self.height);
+        let (row, col) = (self.position / w, self.position % w);
+        let n = self.param(0, 1);
+        match c {
+            'A' => self.position -= min(n, row) * w,
+            'B' => self.position += min(n, h - 1 - row) * w,
+            'C' => self.position += min(n, w - 1 - col),
+            'D' => self.position -= min(n, col),
+            'H' | 'f' => {
+                let row = min(self.param(0, 1), h) - 1;
+                let col = min(self.param(1, 1), w) - 1;
+                self.position = row * w + col;
+            },
+            'J' => match self.param(0, 0) {
+                0 => self.clear_range(self.position, w * h - self.position),
+                1 => self.clear_range(0, self.position + 1),
+                _ => self.clear_range(0, w * h),
+            },
+            'K' => match self.param(0, 0) {
+                0 => self.clear_range(self.position, w - col),
+                1 => self.clear_range(self.position - col, col + 1),
+                _ => self.clear_range(self.position - col, w),
+            },
+            'm' => {
+                // ESC [ m is the same as ESC [ 0 m
+                for i in 0..max(self.num_params, 1) {
+                    self.sgr(self.params[i]);
+                }
+            },
+            's' => self.saved = self.position,
+            'u' => self.position = min(self.saved, w * h - 1),
+            _ => (),
+        }
+    }
+
+    // Select Graphic Rendition: colours and brightness.
+    fn sgr(&mut self, p : usize) {
+        let color = self.color;
+        self.color = match p {
+            0 => default_color,
+            1 => color | 0x0800,
+            22 => color & !0x0800,
+            // Reverse video
+            7 => ((color & 0x0f00) << 4) | ((color & 0xf000) >> 4),
+            30 ..= 37 => (color & 0xf800) | (ansi_colors[p - 30] << 8),
+            39 => (color & 0xf000) | (default_color & 0x0f00),
+            40 ..= 47 => (color & 0x0f00) | (ansi_colors[p - 40] << 12),
+            49 => (color & 0x0f00) | (default_color & 0xf000),
+            90 ..= 97 => (color & 0xf000) | ((ansi_colors[p - 90] | 8) << 8),
+            _ => color,
+        };
+    }
 }
 
 #[cfg(no_console)]
 impl Writer for Console {
     #[inline(always)]
     fn putc(&mut self, c : char) {
-        if self.debug { debugc(c); }
+        if self.debug {
+            debugc(c);
+            klog::putc(c);
+        }
     }
 }
 
@@ -207,13 +408,31 @@ impl Writer for Console {
 impl Writer for Console {
     #[inline(never)]
     fn putc(&mut self, c : char) {
-        if self.debug { debugc(c); }
-        if c == '\n' {
-            self.clear_eol();
-        } else {
-            let value = (c as u8) as u16 | self.color;
-            self.putchar(self.position, value);
-            self.position += 1;
+        if self.debug {
+            debugc(c);
+            serial::putc(c);
+            klog::putc(c);
+        }
+        if self.escape(c) {
+            return;
+        }
+        if !self.fb.is_null() {
+            unsafe { (*self.fb).putc(c); }
+            return;
+        }
+        match c {
+            '\n' => self.clear_eol(),
+            '\r' => self.position -= self.position % self.width,
+            '\t' => self.tab(),
+            // Backspace
+            '\x08' => if self.position % self.width > 0 {
+                self.position -= 1;
+            },
+            _ => {
+                let value = (c as u8) as u16 | self.color;
+                self.putchar(self.position, value);
+                self.position += 1;
+            },
         }
         if self.position >= self.width() * self.height() {
             self.scroll();
@@ -221,62 +440,80 @@ impl Writer for Console {
     }
 }
 
+impl fmt::Write for Console {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        self.write(s);
+        Ok(())
+    }
+}
 
-pub fn clear() { get().clear(); }
-#[inline(never)]
-pub fn newline() { get().newline(); }
+
+pub fn clear() {
+    get().clear();
+    get().sync_cursor();
+}
 #[inline(never)]
-pub fn putc(c : char) { get().putc(c); }
+pub fn newline() {
+    get().newline();
+    get().sync_cursor();
+}
 #[inline(never)]
-pub fn write(string : &str) { get().write(string); }
-pub fn writeCStr(c_str : *const u8) { get().writeCStr(c_str); }
-pub fn writeHex<T : Unsigned>(x : T) { x.writeHex(); }
-pub fn writeInt<T : Signed>(x : T) { x.writeInt(); }
-pub fn writePHex<T : Unsigned>(x : T) { x.writePHex(); }
-pub fn writePtr<T>(x : *const T) { get().writePtr(x); }
-pub fn writeMutPtr<T>(x : *mut T) { get().writeMutPtr(x); }
+pub fn putc(c : char) {
+    get().putc(c);
+    get().sync_cursor();
+}
 #[inline(never)]
-pub fn writeUInt<T : Unsigned>(x : T) { x.writeUInt(); }
+pub fn write(string : &str) {
+    get().write(string);
+    get().sync_cursor();
+}
+pub fn writeCStr(c_str : *const u8) {
+    get().writeCStr(c_str);
+    get().sync_cursor();
+}
+pub fn writeBytes(bytes : &[u8]) {
+    get().writeBytes(bytes);
+    get().sync_cursor();
+}
+
+// Used by kprint! and friends.
+pub fn print(args : fmt::Arguments) {
+    let _ = get().write_fmt(args);
+    get().sync_cursor();
+}
 
-pub trait Unsigned {
-    fn writeHex(self);
-    fn writePHex(self);
-    fn writeUInt(self);
+// Print to the console, like print! and println!.
+macro_rules! kprint {
+    ($($arg:tt)*) => (::con::print(format_args!($($arg)*)));
 }
-pub trait Signed {
-    fn writeInt(self);
+
+macro_rules! kprintln {
+    () => (kprint!("\n"));
+    ($fmt:expr) => (kprint!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (kprint!(concat!($fmt, "\n"), $($arg)*));
 }
-impl Unsigned for usize {
-    fn writeHex(self) { get().writeHex(self); }
-    fn writePHex(self) { get().writePHex(self); }
-    fn writeUInt(self) { get().writeUInt(self); }
+
+// Print a line if messages of the given level are enabled (loglevel= on the
+// command line).
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => (if ::log::level_on($level) {
+        ::klog::set_level($level);
+        kprintln!($($arg)*);
+    });
 }
-impl Signed for isize {
-    fn writeInt(self) { get().writeInt(self); }
+
+macro_rules! kerror {
+    ($($arg:tt)*) => (klog!(::log::Error, $($arg)*));
 }
-macro_rules! unsigned {
-    ($up:ident, $( $x:ident ),* ) => {
-        $(
-        impl Unsigned for $x {
-            fn writeHex(self) { (self as $up).writeHex(); }
-            fn writePHex(self) { (self as $up).writePHex(); }
-            fn writeUInt(self) { (self as $up).writeUInt(); }
-        }
-        )*
-    };
+
+macro_rules! kwarn {
+    ($($arg:tt)*) => (klog!(::log::Warn, $($arg)*));
 }
-macro_rules! signed {
-    ($up:ident, $( $x:ident ),* ) => {
-        $(
-        impl Signed for $x {
-            fn writeInt(self) { (self as $up).writeInt(); }
-        }
-        )*
-    };
+
+macro_rules! kinfo {
+    ($($arg:tt)*) => (klog!(::log::Info, $($arg)*));
+}
+
+macro_rules! kdebug {
+    ($($arg:tt)*) => (klog!(::log::Debug, $($arg)*));
 }
-unsigned!(usize, u64, u32, u16, u8);
-unsigned!(usize, i64, i32, i16, i8);
-unsigned!(usize, isize);
-signed!(isize, i64, i32, i16, i8);
-signed!(isize, u64, u32, u16, u8);
-signed!(isize, usize);
@@ -0,0 +1,79 @@
+// Crash dumps: when the kernel aborts or gets an exception it can't handle,
+// print everything we know about the machine before halting. Output goes
+// through kprintln! and so to every console (screen, serial, debugcon and the
+// kernel log), except the event trace which only goes to serial and debugcon.
+use backtrace;
+use backtrace::backtrace;
+use cpu;
+use dump_processes;
+use mem;
+use util::abort;
+use x86;
+use x86::idt::KernelFrame;
+
+static mut dumping : bool = false;
+
+fn dump_control_regs() {
+    kprintln!("cr0={:#x} cr2={:#x} cr3={:#x} cr4={:#x}", x86::cr0(), x86::cr2(), x86::cr3(), x86::cr4());
+}
+
+fn dump_frame(f : &KernelFrame) {
+    kprintln!("rip={:#018x} rsp={:#018x} rflags={:#x} cs={:#x} ss={:#x}", f.rip, f.rsp, f.rflags, f.cs, f.ss);
+    kprintln!("rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}", f.rax, f.rbx, f.rcx, f.rdx);
+    kprintln!("rsi={:#018x} rdi={:#018x} rbp={:#018x} r8 ={:#018x}", f.rsi, f.rdi, f.rbp, f.r8);
+    kprintln!("r9 ={:#018x} r10={:#018x} r11={:#018x} r12={:#018x}", f.r9, f.r10, f.r11, f.r12);
+    kprintln!("r13={:#018x} r14={:#018x} r15={:#018x}", f.r13, f.r14, f.r15);
+}
+
+// Dump the kernel registers (if we have them), the CPU's process state, all
+// processes, memory usage and a backtrace from rip and rbp. Only the first call
+// does anything, so that a fault or abort while dumping doesn't loop.
+pub fn dump(frame : Option<&KernelFrame>, rip : u64, rbp : u64) {
+    unsafe {
+        if dumping {
+            return;
+        }
+        dumping = true;
+    }
+    match frame {
+        Some(f) => dump_frame(f),
+        None => kprintln!("rip={:#018x} rsp={:#018x} rbp={:#018x} rflags={:#x}", rip, x86::rsp(), rbp, x86::get_rflags()),
+    }
+    dump_control_regs();
+    match cpu().process {
+        Some(ref p) => kprintln!("in process {} rip={:#x}", p.id, p.rip),
+        None => kprintln!("not in a process"),
+    }
+    dump_processes();
+    mem::get().stat();
+    backtrace(rip, rbp);
+    cpu().events.dump();
+}
+
+// Like dump, from the caller of dump_here.
+#[inline(never)]
+pub fn dump_here() {
+    match backtrace::frame(x86::rbp()) {
+        Some((rbp, rip)) => dump(None, rip, rbp),
+        None => (),
+    }
+}
+
+static exception_names : [&'static str; 32] = [
+    "#DE", "#DB", "NMI", "#BP", "#OF", "#BR", "#UD", "#NM",
+    "#DF", "", "#TS", "#NP", "#SS", "#GP", "#PF", "",
+    "#MF", "#AC", "#MC", "#XM", "#VE", "", "", "",
+    "", "", "", "", "", "", "#SX", ""];
+
+pub fn exception_name(vec : u64) -> &'static str {
+    if vec < 32 { exception_names[vec as usize] } else { "" }
+}
+
+// Exceptions in the kernel other than debug traps end up here.
+#[no_mangle]
+pub extern "C" fn kernel_fault(frame : &KernelFrame, error : u64) -> ! {
+    kerror!("kernel exception {} {} error={:#x} at {:#x}", frame.vector,
+        exception_name(frame.vector), error, frame.rip);
+    dump(Some(frame), frame.rip, frame.rbp);
+    abort("Kernel exception");
+}
@@ -0,0 +1,220 @@
+// Debugging other processes from user space (the DEBUG syscall). Debugging
+// goes through a handle to the target: the handle NEWPROC gives for a new
+// process carries the capability, and privileged processes can debug through
+// any handle. A debugger attaches, and can then suspend and resume the target,
+// get and set its registers, read and write its memory, and single-step it.
+//
+// Breakpoints are int3 instructions written into the target's memory. When
+// the target gets a #DB or #BP it stops, and the debugger receives a message
+// from the kernel: rax = DEBUG, rdi = the debugger's handle for the target,
+// rsi = the vector (1 or 3) and rdx = rip (after the int3 for #BP).
+use core::cmp::min;
+use core::mem::size_of;
+use core::slice;
+
+use cpu;
+use klog;
+use process;
+use process::Process;
+use syscall;
+use syscall::err;
+use x86::rflags;
+
+pub mod op {
+    // Start debugging the process at the other end of the handle
+    pub const ATTACH : u64 = 0;
+    // Stop debugging it, and resume it if it's stopped
+    pub const DETACH : u64 = 1;
+    pub const SUSPEND : u64 = 2;
+    // Resume, with single-stepping if arg is 1
+    pub const RESUME : u64 = 3;
+    // Copy a Regs record to or from buf
+    pub const GETREGS : u64 = 4;
+    pub const SETREGS : u64 = 5;
+    // Copy len bytes between the target's memory at arg and buf
+    pub const READ : u64 = 6;
+    pub const WRITE : u64 = 7;
+}
+
+// Registers of a stopped process, in gdb's order.
+#[repr(C)]
+pub struct Regs {
+    pub rax : u64,
+    pub rbx : u64,
+    pub rcx : u64,
+    pub rdx : u64,
+    pub rsi : u64,
+    pub rdi : u64,
+    pub rbp : u64,
+    pub rsp : u64,
+    pub r8 : u64,
+    pub r9 : u64,
+    pub r10 : u64,
+    pub r11 : u64,
+    pub r12 : u64,
+    pub r13 : u64,
+    pub r14 : u64,
+    pub r15 : u64,
+    pub rip : u64,
+    pub rflags : u64,
+}
+
+// CF, PF, AF, ZF, SF, TF, DF and OF: the flags a debugger may change.
+const user_flags : u64 = 0xdd5;
+
+fn get_regs(p : &mut Process) -> Regs {
+    let (rip, rflags) = (p.rip, p.rflags);
+    let in_syscall = p.is(process::FastRet);
+    let r = p.regs();
+    let mut v = Regs {
+        rax : r.rax, rbx : r.rbx, rcx : r.rcx, rdx : r.rdx,
+        rsi : r.rsi, rdi : r.rdi, rbp : r.rbp, rsp : r.rsp,
+        r8 : r.r8, r9 : r.r9, r10 : r.r10, r11 : r.r11,
+        r12 : r.r12, r13 : r.r13, r14 : r.r14, r15 : r.r15,
+        rip : rip, rflags : rflags,
+    };
+    if in_syscall {
+        // Only the callee-saved registers were saved on syscall entry. Show
+        // the rest as the syscall returns them (see fastret): rcx and r11
+        // get rip and rflags, rsi a copy of rax and the others 0.
+        v.rcx = rip;
+        v.r11 = rflags;
+        v.rsi = v.rax;
+        v.rdx = 0; v.rdi = 0;
+        v.r8 = 0; v.r9 = 0; v.r10 = 0;
+    }
+    v
+}
+
+fn set_regs(p : &mut Process, v : &Regs) {
+    p.rip = v.rip;
+    p.rflags = (p.rflags & !user_flags) | (v.rflags & user_flags);
+    {
+        let r = p.regs();
+        r.rax = v.rax; r.rbx = v.rbx; r.rcx = v.rcx; r.rdx = v.rdx;
+        r.rsi = v.rsi; r.rdi = v.rdi; r.rbp = v.rbp; r.rsp = v.rsp;
+        r.r8 = v.r8; r.r9 = v.r9; r.r10 = v.r10; r.r11 = v.r11;
+        r.r12 = v.r12; r.r13 = v.r13; r.r14 = v.r14; r.r15 = v.r15;
+    }
+    // fastret only restores some of them
+    p.unset(process::FastRet);
+}
+
+fn resume(t : &mut Process, step : bool) {
+    if step {
+        t.rflags |= rflags::TF;
+    } else {
+        t.rflags &= !rflags::TF;
+    }
+    if t.is(process::DebugStop) {
+        t.unset(process::DebugStop);
+        match t.debugger() {
+            Some(d) => d.remove_waiter(t),
+            None => (),
+        }
+    }
+    if t.is(process::Stopped) {
+        t.unset(process::Stopped);
+        t.trap = 0;
+        // Still blocked in IPC or in KLOG WAIT, then it gets queued when
+        // that finishes.
+        if t.is_runnable() && !klog::is_waiter(t) {
+            cpu().queue(t);
+        }
+    }
+}
+
+// Copy len bytes from one address space to the other, a bit at a time.
+fn copy(from : &mut Process, src : u64, to : &mut Process, dst : u64, len : u64, write : bool) -> u64 {
+    let mut buf = [0u8; 128];
+    let mut done = 0;
+    while done < len {
+        let n = min(len - done, buf.len() as u64) as usize;
+        if !from.aspace().copy_from_user(src.wrapping_add(done), &mut buf[..n]) {
+            return err::EFAULT;
+        }
+        let dst = dst.wrapping_add(done);
+        let ok = if write {
+            // Breakpoints go in read-only program text
+            to.aspace().debug_write(dst, &buf[..n])
+        } else {
+            to.aspace().copy_to_user(dst, &buf[..n])
+        };
+        if !ok {
+            return err::EFAULT;
+        }
+        done += n as u64;
+    }
+    len
+}
+
+// Run op on the process at the other end of handle id in p. Returns 0 (or the
+// length copied for READ and WRITE), EPERM if p may not debug it (or hasn't
+// attached), EBUSY if it has another debugger or isn't stopped for GETREGS
+// and SETREGS, EINVAL for bad arguments, or EFAULT for bad buffers.
+pub fn debug(p : &mut Process, op : u64, id : u64, arg : u64, buf : u64, len : u64) -> u64 {
+    let (t, cap) = match p.find_handle(id) {
+        Some(h) => (h.process(), h.debug),
+        None => return err::EINVAL,
+    };
+    if t as *mut Process == p as *mut Process {
+        return err::EINVAL;
+    }
+    let attached = match t.debugger() {
+        Some(d) => d as *mut Process == p as *mut Process && t.debugger_handle == id,
+        None => false,
+    };
+    if op == op::ATTACH {
+        if !cap && !p.is(process::Privileged) {
+            return err::EPERM;
+        }
+        if t.debugger().is_some() && !attached {
+            return err::EBUSY;
+        }
+        t.set_debugger(p, id);
+        return 0;
+    }
+    if !attached {
+        return err::EPERM;
+    }
+    let size = size_of::<Regs>() as u64;
+    match op {
+        op::DETACH => {
+            resume(t, false);
+            t.set_debugger(0 as *mut Process, 0);
+        },
+        op::SUSPEND => t.set(process::Stopped),
+        op::RESUME => resume(t, arg == 1),
+        op::GETREGS | op::SETREGS if !t.is(process::Stopped) => return err::EBUSY,
+        op::GETREGS | op::SETREGS if len < size => return err::EINVAL,
+        op::GETREGS => {
+            let regs = get_regs(t);
+            let bytes = unsafe { slice::from_raw_parts(&regs as *const Regs as *const u8, size as usize) };
+            if !p.aspace().copy_to_user(buf, bytes) {
+                return err::EFAULT;
+            }
+        },
+        op::SETREGS => {
+            let mut regs = get_regs(t);
+            let bytes = unsafe { slice::from_raw_parts_mut(&mut regs as *mut Regs as *mut u8, size as usize) };
+            if !p.aspace().copy_from_user(buf, bytes) {
+                return err::EFAULT;
+            }
+            if regs.rip >= 1 << 47 {
+                return err::EINVAL;
+            }
+            set_regs(t, &regs);
+        },
+        op::READ => return copy(t, arg, p, buf, len, false),
+        op::WRITE => return copy(p, buf, t, arg, len, true),
+        _ => return err::EINVAL,
+    }
+    0
+}
+
+// #DB or #BP in p, which has a debugger: stop it and tell the debugger.
+pub fn trap(p : &mut Process, vec : u8) -> ! {
+    p.set(process::Stopped);
+    p.trap = vec;
+    syscall::report_stop(p);
+}
@@ -104,7 +104,7 @@ impl<V : DictItem> Dict<V> where V::Key: Ord + Copy {
     pub fn remove_range_exclusive(&mut self, start: V::Key, end: V::Key) {
         unsafe {
             let mut p : *mut *mut V = &mut self.root;
-            while (*p).is_null() {
+            while !(*p).is_null() {
                 let item = *p;
                 if start < node(item).key && node(item).key < end {
                     *p = node(item).right;
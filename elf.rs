@@ -0,0 +1,175 @@
+// Loading of ELF64 executables from boot modules.
+use core::cmp::min;
+use core::mem::size_of;
+
+use aspace::AddressSpace;
+use aspace::mapflag;
+use aspace::MapFlag;
+use image::Image;
+
+const ELFCLASS64 : u8 = 2;
+const ELFDATA2LSB : u8 = 1;
+const ET_EXEC : u16 = 2;
+const EM_X86_64 : u16 = 62;
+const PT_LOAD : u32 = 1;
+
+// Segment flags. Same values as the corresponding mapflags.
+const PF_X : u32 = 1;
+const PF_W : u32 = 2;
+const PF_R : u32 = 4;
+
+// End of the user half of the address space.
+const user_end : u64 = 1 << 47;
+// The boot info and initial stack pages set up by main.
+const low_start : u64 = 0xfe000;
+const low_end : u64 = 0x100000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Header {
+    ident       : [u8; 16],
+    e_type      : u16,
+    machine     : u16,
+    version     : u32,
+    entry       : u64,
+    phoff       : u64,
+    shoff       : u64,
+    flags       : u32,
+    ehsize      : u16,
+    phentsize   : u16,
+    phnum       : u16,
+    shentsize   : u16,
+    shnum       : u16,
+    shstrndx    : u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ProgramHeader {
+    p_type      : u32,
+    flags       : u32,
+    offset      : u64,
+    vaddr       : u64,
+    paddr       : u64,
+    filesz      : u64,
+    memsz       : u64,
+    align       : u64,
+}
+
+pub struct Loaded {
+    pub entry : u64,
+    // Some segment maps the image directly, so the image must be kept as long
+    // as the process exists.
+    pub uses_image : bool,
+}
+
+pub fn is_elf(image : &Image) -> bool {
+    image.starts_with(b"\x7fELF")
+}
+
+fn header(image : &Image) -> Result<Header, &'static str> {
+    let h : Header = match image.read(0) {
+        Some(h) => h,
+        None => return Err("truncated header"),
+    };
+    if !is_elf(image) {
+        return Err("bad magic");
+    }
+    if h.ident[4] != ELFCLASS64 || h.ident[5] != ELFDATA2LSB {
+        return Err("not a little-endian ELF64 file");
+    }
+    if h.e_type != ET_EXEC || h.machine != EM_X86_64 {
+        return Err("not an x86-64 executable");
+    }
+    if h.phentsize as usize != size_of::<ProgramHeader>() {
+        return Err("bad program header size");
+    }
+    Ok(h)
+}
+
+fn check_segment(image : &Image, ph : &ProgramHeader) -> Result<(), &'static str> {
+    if ph.filesz > ph.memsz {
+        return Err("segment file size larger than memory size");
+    }
+    if ph.offset > image.len() || image.len() - ph.offset < ph.filesz {
+        return Err("segment outside of file");
+    }
+    if ph.vaddr >= user_end || user_end - ph.vaddr < ph.memsz {
+        return Err("segment outside of user memory");
+    }
+    if ph.vaddr < low_end && ph.vaddr + ph.memsz > low_start {
+        return Err("segment overlaps the stack or boot info page");
+    }
+    Ok(())
+}
+
+fn map_flags(ph : &ProgramHeader) -> MapFlag {
+    let mut flags = 0;
+    if ph.flags & PF_R != 0 { flags |= mapflag::R; }
+    if ph.flags & PF_W != 0 { flags |= mapflag::W; }
+    if ph.flags & PF_X != 0 { flags |= mapflag::X; }
+    flags
+}
+
+// Copy the file part of a segment into freshly allocated anonymous pages. The
+// rest of the pages (and the BSS) are left zero.
+fn copy_segment(aspace : &mut AddressSpace, image : &Image, ph : &ProgramHeader) {
+    let mut vaddr = ph.vaddr;
+    let mut offset = ph.offset;
+    let end = ph.vaddr + ph.filesz;
+    while vaddr < end {
+        let n = min(end - vaddr, 0x1000 - (vaddr & 0xfff));
+        let page = aspace.prefill_anon(vaddr);
+        image.copy(offset, unsafe { page.offset((vaddr & 0xfff) as isize) }, n);
+        vaddr += n;
+        offset += n;
+    }
+}
+
+// Map the PT_LOAD segments of image into aspace. Read-only segments are mapped
+// directly from the image when it's in contiguous memory and the alignment
+// allows it, everything else is copied.
+pub fn load(aspace : &mut AddressSpace, image : &Image) -> Result<Loaded, &'static str> {
+    use aspace::mapflag::*;
+
+    let h = try!(header(image));
+    let mut uses_image = false;
+    let mut prev_end = 0;
+    for i in 0..h.phnum as u64 {
+        let ph : ProgramHeader = match image.read(h.phoff + i * size_of::<ProgramHeader>() as u64) {
+            Some(ph) => ph,
+            None => return Err("program header outside of file"),
+        };
+        if ph.p_type != PT_LOAD || ph.memsz == 0 {
+            continue;
+        }
+        try!(check_segment(image, &ph));
+
+        let flags = map_flags(&ph);
+        let start = ph.vaddr & !0xfff;
+        let end = (ph.vaddr + ph.memsz + 0xfff) & !0xfff;
+        // Each page gets the flags of one segment, so segments must be
+        // sorted and must not share pages.
+        if start < prev_end {
+            return Err("overlapping segments");
+        }
+        prev_end = end;
+        let direct = match image.paddr() {
+            Some(paddr) if (flags & W) == 0 && ph.filesz == ph.memsz
+                && ((paddr + ph.offset) & 0xfff) == (ph.vaddr & 0xfff) => Some(paddr + ph.offset),
+            _ => None,
+        };
+        match direct {
+            Some(paddr) => {
+                aspace.mapcard_set(start, 0, (paddr & !0xfff).wrapping_sub(start), Phys | flags);
+                uses_image = true;
+            },
+            None => {
+                aspace.mapcard_set(start, 0, 0, Anon | flags);
+                copy_segment(aspace, image, &ph);
+            }
+        }
+        aspace.mapcard_end(end);
+    }
+    Ok(Loaded { entry : h.entry, uses_image : uses_image })
+}
@@ -0,0 +1,183 @@
+/* evdecode.c: decode the kernel's event trace dump (see events.rs).
+
+   Reads a captured serial or debugcon log on stdin and prints the events of
+   the last dump in it as a timeline, or with -j as Chrome trace JSON for
+   chrome://tracing or Perfetto. Times are in TSC cycles since the first
+   event, or in microseconds with -m <TSC MHz>. */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define MAX_EVENTS 65536
+#define MAX_PROCS 4096
+
+enum {
+    SYSCALL = 1, SYSRET, TRANSFER, PULSE, QUEUE, SWITCH, PFAULT, IRQ,
+};
+
+struct event {
+    unsigned long long time;
+    unsigned kind;
+    unsigned proc;
+    unsigned long long a, b;
+};
+
+static const char *syscall_names[] = {
+    "recv", "map", "pfault", "unmap", "hmod", "newproc", "write", "portio",
+    "grant", "pulse", "logctl", "bootinfo", "klog", "trace", "info", "debug",
+    "writebuf",
+};
+#define NUM_SYSCALLS (sizeof(syscall_names) / sizeof(*syscall_names))
+#define USER 17
+
+static struct event events[MAX_EVENTS];
+static int num_events;
+static double mhz;
+/* Processes in a syscall, for ending the slices in the JSON output */
+static char in_syscall[MAX_PROCS];
+
+static double ts(const struct event *e)
+{
+    double t = e->time - events[0].time;
+    return mhz > 0 ? t / mhz : t;
+}
+
+static const char *syscall_name(unsigned long long nr, char *buf)
+{
+    if (nr < NUM_SYSCALLS)
+        return syscall_names[nr];
+    if (nr >= USER)
+        sprintf(buf, "%s %#llx", (nr & 0x300) == 0x100 ? "call" : "send", nr & 0xff);
+    else
+        sprintf(buf, "syscall %llu", nr);
+    return buf;
+}
+
+/* One line describing e, without the time and process. */
+static void describe(const struct event *e, char *out)
+{
+    char buf[32];
+    switch (e->kind) {
+    case SYSCALL:
+        sprintf(out, "%s(%#llx)", syscall_name(e->a, buf), e->b);
+        break;
+    case SYSRET:
+        sprintf(out, "= %#llx", e->a);
+        break;
+    case TRANSFER:
+        sprintf(out, "message %#llx to proc %llu", e->b, e->a);
+        break;
+    case PULSE:
+        sprintf(out, "pulses %#llx on handle %#llx", e->b, e->a);
+        break;
+    case QUEUE:
+        sprintf(out, "queued%s", e->a ? " (already)" : "");
+        break;
+    case SWITCH:
+        sprintf(out, "runs at %#llx", e->a);
+        break;
+    case PFAULT:
+        sprintf(out, "page fault at %#llx error %#llx", e->a, e->b);
+        break;
+    case IRQ:
+        sprintf(out, "irq %llu", e->a);
+        break;
+    default:
+        sprintf(out, "event %u %#llx %#llx", e->kind, e->a, e->b);
+        break;
+    }
+}
+
+static void timeline(void)
+{
+    char desc[128];
+    for (int i = 0; i < num_events; i++) {
+        const struct event *e = &events[i];
+        describe(e, desc);
+        if (e->proc)
+            printf("%14.3f [%u] %s\n", ts(e), e->proc, desc);
+        else
+            printf("%14.3f     %s\n", ts(e), desc);
+    }
+}
+
+static void json_event(const char *sep, const struct event *e, const char *ph, const char *name)
+{
+    printf("%s{\"name\":\"%s\",\"ph\":\"%s\",\"ts\":%.3f,\"pid\":0,\"tid\":%u%s}",
+        sep, name, ph, ts(e), e->proc, *ph == 'i' ? ",\"s\":\"t\"" : "");
+}
+
+/* Syscalls are slices from the call until the process runs again, so time
+   spent blocked in a syscall shows up as part of it. Everything else is an
+   instant event. */
+static void chrome_json(void)
+{
+    char name[128];
+    const char *sep = "";
+    printf("{\"traceEvents\":[\n");
+    for (int i = 0; i < num_events; i++) {
+        const struct event *e = &events[i];
+        int open = e->proc < MAX_PROCS && in_syscall[e->proc];
+        if (e->kind == SYSCALL) {
+            if (open) {
+                json_event(sep, e, "E", "");
+                sep = ",\n";
+            }
+            json_event(sep, e, "B", syscall_name(e->a, name));
+        } else if (e->kind == SWITCH && open) {
+            json_event(sep, e, "E", "");
+        } else {
+            describe(e, name);
+            json_event(sep, e, "i", name);
+        }
+        if (e->proc < MAX_PROCS) {
+            if (e->kind == SYSCALL)
+                in_syscall[e->proc] = 1;
+            else if (e->kind == SWITCH)
+                in_syscall[e->proc] = 0;
+        }
+        sep = ",\n";
+    }
+    printf("\n]}\n");
+}
+
+int main(int argc, char *argv[])
+{
+    char line[256];
+    int json = 0;
+
+    for (int i = 1; i < argc; i++) {
+        if (!strcmp(argv[i], "-j")) {
+            json = 1;
+        } else if (!strcmp(argv[i], "-m") && i + 1 < argc) {
+            mhz = atof(argv[++i]);
+        } else {
+            fprintf(stderr, "usage: %s [-j] [-m tsc-mhz] < log\n", argv[0]);
+            return 2;
+        }
+    }
+
+    while (fgets(line, sizeof(line), stdin)) {
+        struct event e;
+        unsigned long long total, lost;
+        if (sscanf(line, "events: begin %llu %llu", &total, &lost) == 2) {
+            /* A later dump replaces an earlier one */
+            num_events = 0;
+            if (lost)
+                fprintf(stderr, "%llu older events were overwritten\n", lost);
+        } else if (sscanf(line, "ev %llx %x %x %llx %llx", &e.time, &e.kind, &e.proc, &e.a, &e.b) == 5) {
+            if (num_events < MAX_EVENTS)
+                events[num_events++] = e;
+        }
+    }
+    if (num_events == 0) {
+        fprintf(stderr, "no events found\n");
+        return 1;
+    }
+    if (json)
+        chrome_json();
+    else
+        timeline();
+    return 0;
+}
@@ -0,0 +1,129 @@
+// Binary event trace: with events=<pages> on the command line, each CPU keeps
+// a ring buffer of timestamped records of what the kernel does, for finding
+// IPC ordering bugs that text logging is too slow (and too verbose) to catch.
+// The oldest records are overwritten when it's full.
+//
+// The buffer is dumped with the monitor's events command and in crash dumps,
+// as hex lines on the serial port and debugcon. evdecode.c turns a captured
+// log into a timeline or a Chrome trace.
+use core::fmt;
+use core::fmt::Write;
+
+use con;
+use mem;
+use serial;
+use x86::rdtsc;
+
+pub mod kind {
+    // a = syscall number, b = first argument
+    pub const SYSCALL : u32 = 1;
+    // a = result
+    pub const SYSRET : u32 = 2;
+    // proc = sender, a = recipient, b = message
+    pub const TRANSFER : u32 = 3;
+    // a = handle, b = pulses
+    pub const PULSE : u32 = 4;
+    // a = 1 if it was already queued
+    pub const QUEUE : u32 = 5;
+    // a = rip
+    pub const SWITCH : u32 = 6;
+    // a = fault address, b = error code
+    pub const PFAULT : u32 = 7;
+    // proc = 0, a = vector
+    pub const IRQ : u32 = 8;
+}
+
+// 32 bytes, little-endian. evdecode.c knows this layout.
+#[repr(C)]
+struct Event {
+    time : u64,
+    kind : u32,
+    // Process id, 0 for none
+    proc_id : u32,
+    a : u64,
+    b : u64,
+}
+
+const per_page : usize = 4096 / 32;
+pub const max_pages : usize = 64;
+
+pub struct PerCpu {
+    pages : [*mut Event; max_pages],
+    num_pages : usize,
+    // Number of events recorded so far, including overwritten ones
+    next : u64,
+}
+
+impl PerCpu {
+    pub fn new() -> PerCpu {
+        PerCpu { pages : [0 as *mut Event; max_pages], num_pages : 0, next : 0 }
+    }
+
+    // Allocate n pages of buffer (0 to keep it off).
+    pub fn init(&mut self, memory : &mut mem::PerCpu, n : usize) {
+        while self.num_pages < n && self.num_pages < max_pages {
+            self.pages[self.num_pages] = memory.alloc_frame_panic();
+            self.num_pages += 1;
+        }
+    }
+
+    fn size(&self) -> u64 {
+        (self.num_pages * per_page) as u64
+    }
+
+    fn get(&self, i : u64) -> &Event {
+        let i = (i % self.size()) as usize;
+        unsafe { &*self.pages[i / per_page].offset((i % per_page) as isize) }
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, kind : u32, proc_id : u32, a : u64, b : u64) {
+        if self.num_pages != 0 {
+            self.record_(kind, proc_id, a, b);
+        }
+    }
+
+    #[inline(never)]
+    fn record_(&mut self, kind : u32, proc_id : u32, a : u64, b : u64) {
+        let i = (self.next % self.size()) as usize;
+        self.next += 1;
+        unsafe {
+            *self.pages[i / per_page].offset((i % per_page) as isize) = Event {
+                time : rdtsc(),
+                kind : kind,
+                proc_id : proc_id,
+                a : a,
+                b : b,
+            };
+        }
+    }
+
+    // Write the buffer, oldest first, to the serial port and debugcon.
+    pub fn dump(&self) {
+        let mut out = Out;
+        if self.num_pages == 0 {
+            let _ = writeln!(out, "events: off, see the events= option");
+            return;
+        }
+        let first = if self.next > self.size() { self.next - self.size() } else { 0 };
+        let _ = writeln!(out, "events: begin {} {}", self.next - first, first);
+        for i in first..self.next {
+            let e = self.get(i);
+            let _ = writeln!(out, "ev {:016x} {:x} {:x} {:x} {:x}", e.time, e.kind, e.proc_id, e.a, e.b);
+        }
+        let _ = writeln!(out, "events: end");
+    }
+}
+
+// Serial and debugcon only: the dump is for capturing, not for the screen.
+struct Out;
+
+impl fmt::Write for Out {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        for c in s.chars() {
+            con::debugc(c);
+            serial::putc(c);
+        }
+        Ok(())
+    }
+}
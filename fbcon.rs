@@ -0,0 +1,270 @@
+// Text console on a linear framebuffer, for when the boot loader set up a
+// graphics mode and there's no VGA text buffer. Characters are drawn with an
+// 8x8 bitmap font.
+use core::cmp::min;
+
+use aspace;
+use boot;
+use con;
+use con::Writer;
+use mboot;
+use start32::PhysAddr;
+
+const glyph_width : usize = 8;
+const glyph_height : usize = 8;
+
+// 8x8 font for ASCII 32..126. One byte per row, the lowest bit is the
+// leftmost pixel. (Public domain font8x8 by Daniel Hepper.)
+static font : [[u8; 8]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], //  
+    [0x18, 0x3c, 0x3c, 0x18, 0x18, 0x00, 0x18, 0x00], // !
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // "
+    [0x36, 0x36, 0x7f, 0x36, 0x7f, 0x36, 0x36, 0x00], // #
+    [0x0c, 0x3e, 0x03, 0x1e, 0x30, 0x1f, 0x0c, 0x00], // $
+    [0x00, 0x63, 0x33, 0x18, 0x0c, 0x66, 0x63, 0x00], // %
+    [0x1c, 0x36, 0x1c, 0x6e, 0x3b, 0x33, 0x6e, 0x00], // &
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // '
+    [0x18, 0x0c, 0x06, 0x06, 0x06, 0x0c, 0x18, 0x00], // (
+    [0x06, 0x0c, 0x18, 0x18, 0x18, 0x0c, 0x06, 0x00], // )
+    [0x00, 0x66, 0x3c, 0xff, 0x3c, 0x66, 0x00, 0x00], // *
+    [0x00, 0x0c, 0x0c, 0x3f, 0x0c, 0x0c, 0x00, 0x00], // +
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c, 0x06], // ,
+    [0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, 0x00], // -
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c, 0x00], // .
+    [0x60, 0x30, 0x18, 0x0c, 0x06, 0x03, 0x01, 0x00], // /
+    [0x3e, 0x63, 0x73, 0x7b, 0x6f, 0x67, 0x3e, 0x00], // 0
+    [0x0c, 0x0e, 0x0c, 0x0c, 0x0c, 0x0c, 0x3f, 0x00], // 1
+    [0x1e, 0x33, 0x30, 0x1c, 0x06, 0x33, 0x3f, 0x00], // 2
+    [0x1e, 0x33, 0x30, 0x1c, 0x30, 0x33, 0x1e, 0x00], // 3
+    [0x38, 0x3c, 0x36, 0x33, 0x7f, 0x30, 0x78, 0x00], // 4
+    [0x3f, 0x03, 0x1f, 0x30, 0x30, 0x33, 0x1e, 0x00], // 5
+    [0x1c, 0x06, 0x03, 0x1f, 0x33, 0x33, 0x1e, 0x00], // 6
+    [0x3f, 0x33, 0x30, 0x18, 0x0c, 0x0c, 0x0c, 0x00], // 7
+    [0x1e, 0x33, 0x33, 0x1e, 0x33, 0x33, 0x1e, 0x00], // 8
+    [0x1e, 0x33, 0x33, 0x3e, 0x30, 0x18, 0x0e, 0x00], // 9
+    [0x00, 0x0c, 0x0c, 0x00, 0x00, 0x0c, 0x0c, 0x00], // :
+    [0x00, 0x0c, 0x0c, 0x00, 0x00, 0x0c, 0x0c, 0x06], // ;
+    [0x18, 0x0c, 0x06, 0x03, 0x06, 0x0c, 0x18, 0x00], // <
+    [0x00, 0x00, 0x3f, 0x00, 0x00, 0x3f, 0x00, 0x00], // =
+    [0x06, 0x0c, 0x18, 0x30, 0x18, 0x0c, 0x06, 0x00], // >
+    [0x1e, 0x33, 0x30, 0x18, 0x0c, 0x00, 0x0c, 0x00], // ?
+    [0x3e, 0x63, 0x7b, 0x7b, 0x7b, 0x03, 0x1e, 0x00], // @
+    [0x0c, 0x1e, 0x33, 0x33, 0x3f, 0x33, 0x33, 0x00], // A
+    [0x3f, 0x66, 0x66, 0x3e, 0x66, 0x66, 0x3f, 0x00], // B
+    [0x3c, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3c, 0x00], // C
+    [0x1f, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1f, 0x00], // D
+    [0x7f, 0x46, 0x16, 0x1e, 0x16, 0x46, 0x7f, 0x00], // E
+    [0x7f, 0x46, 0x16, 0x1e, 0x16, 0x06, 0x0f, 0x00], // F
+    [0x3c, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7c, 0x00], // G
+    [0x33, 0x33, 0x33, 0x3f, 0x33, 0x33, 0x33, 0x00], // H
+    [0x1e, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // I
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1e, 0x00], // J
+    [0x67, 0x66, 0x36, 0x1e, 0x36, 0x66, 0x67, 0x00], // K
+    [0x0f, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7f, 0x00], // L
+    [0x63, 0x77, 0x7f, 0x7f, 0x6b, 0x63, 0x63, 0x00], // M
+    [0x63, 0x67, 0x6f, 0x7b, 0x73, 0x63, 0x63, 0x00], // N
+    [0x1c, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1c, 0x00], // O
+    [0x3f, 0x66, 0x66, 0x3e, 0x06, 0x06, 0x0f, 0x00], // P
+    [0x1e, 0x33, 0x33, 0x33, 0x3b, 0x1e, 0x38, 0x00], // Q
+    [0x3f, 0x66, 0x66, 0x3e, 0x36, 0x66, 0x67, 0x00], // R
+    [0x1e, 0x33, 0x07, 0x0e, 0x38, 0x33, 0x1e, 0x00], // S
+    [0x3f, 0x2d, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // T
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3f, 0x00], // U
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1e, 0x0c, 0x00], // V
+    [0x63, 0x63, 0x63, 0x6b, 0x7f, 0x77, 0x63, 0x00], // W
+    [0x63, 0x63, 0x36, 0x1c, 0x1c, 0x36, 0x63, 0x00], // X
+    [0x33, 0x33, 0x33, 0x1e, 0x0c, 0x0c, 0x1e, 0x00], // Y
+    [0x7f, 0x63, 0x31, 0x18, 0x4c, 0x66, 0x7f, 0x00], // Z
+    [0x1e, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1e, 0x00], // [
+    [0x03, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x40, 0x00], // backslash
+    [0x1e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1e, 0x00], // ]
+    [0x08, 0x1c, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // ^
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff], // _
+    [0x0c, 0x0c, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00], // `
+    [0x00, 0x00, 0x1e, 0x30, 0x3e, 0x33, 0x6e, 0x00], // a
+    [0x07, 0x06, 0x06, 0x3e, 0x66, 0x66, 0x3b, 0x00], // b
+    [0x00, 0x00, 0x1e, 0x33, 0x03, 0x33, 0x1e, 0x00], // c
+    [0x38, 0x30, 0x30, 0x3e, 0x33, 0x33, 0x6e, 0x00], // d
+    [0x00, 0x00, 0x1e, 0x33, 0x3f, 0x03, 0x1e, 0x00], // e
+    [0x1c, 0x36, 0x06, 0x0f, 0x06, 0x06, 0x0f, 0x00], // f
+    [0x00, 0x00, 0x6e, 0x33, 0x33, 0x3e, 0x30, 0x1f], // g
+    [0x07, 0x06, 0x36, 0x6e, 0x66, 0x66, 0x67, 0x00], // h
+    [0x0c, 0x00, 0x0e, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // i
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1e], // j
+    [0x07, 0x06, 0x66, 0x36, 0x1e, 0x36, 0x67, 0x00], // k
+    [0x0e, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // l
+    [0x00, 0x00, 0x33, 0x7f, 0x7f, 0x6b, 0x63, 0x00], // m
+    [0x00, 0x00, 0x1f, 0x33, 0x33, 0x33, 0x33, 0x00], // n
+    [0x00, 0x00, 0x1e, 0x33, 0x33, 0x33, 0x1e, 0x00], // o
+    [0x00, 0x00, 0x3b, 0x66, 0x66, 0x3e, 0x06, 0x0f], // p
+    [0x00, 0x00, 0x6e, 0x33, 0x33, 0x3e, 0x30, 0x78], // q
+    [0x00, 0x00, 0x3b, 0x6e, 0x66, 0x06, 0x0f, 0x00], // r
+    [0x00, 0x00, 0x3e, 0x03, 0x1e, 0x30, 0x1f, 0x00], // s
+    [0x08, 0x0c, 0x3e, 0x0c, 0x0c, 0x2c, 0x18, 0x00], // t
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6e, 0x00], // u
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1e, 0x0c, 0x00], // v
+    [0x00, 0x00, 0x63, 0x6b, 0x7f, 0x7f, 0x36, 0x00], // w
+    [0x00, 0x00, 0x63, 0x36, 0x1c, 0x36, 0x63, 0x00], // x
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3e, 0x30, 0x1f], // y
+    [0x00, 0x00, 0x3f, 0x19, 0x0c, 0x26, 0x3f, 0x00], // z
+    [0x38, 0x0c, 0x0c, 0x07, 0x0c, 0x0c, 0x38, 0x00], // {
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00], // |
+    [0x07, 0x0c, 0x0c, 0x38, 0x0c, 0x0c, 0x07, 0x00], // }
+    [0x6e, 0x3b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ~
+];
+
+pub struct FbCon {
+    base : *mut u8,
+    pitch : usize,
+    // Bytes per pixel
+    bytes : usize,
+    cols : usize,
+    rows : usize,
+    col : usize,
+    row : usize,
+    fg : u32,
+    bg : u32,
+}
+
+static mut fbcon : FbCon = FbCon {
+    base : 0 as *mut u8, pitch : 0, bytes : 0, cols : 0, rows : 0,
+    col : 0, row : 0, fg : 0, bg : 0,
+};
+
+// Scale an 8-bit color component to bits bits and put it at shift.
+fn component(value : u8, shift : u8, bits : u8) -> u32 {
+    if bits == 0 {
+        return 0;
+    }
+    ((value as u32) >> (8 - min(bits, 8))) << shift
+}
+
+// The pixel value closest to the given color.
+fn pixel(fb : &boot::Framebuffer, r : u8, g : u8, b : u8) -> u32 {
+    if fb.fbtype == mboot::FBType::RGB as u8 {
+        let f = &fb.format;
+        return component(r, f.red_shift, f.red_mask)
+            | component(g, f.green_shift, f.green_mask)
+            | component(b, f.blue_shift, f.blue_mask);
+    }
+    // Indexed: find the closest palette entry. Without a palette, assume the
+    // standard VGA one.
+    if fb.palette_count == 0 {
+        return if r == 0 && g == 0 && b == 0 { 0 } else { 7 };
+    }
+    let mut best = 0;
+    let mut best_dist = !0u32;
+    for i in 0..fb.palette_count as u64 {
+        let p : *const u8 = PhysAddr(fb.palette + 3 * i);
+        let dist = unsafe {
+            ((*p) as i32 - r as i32).abs() as u32
+            + ((*p.offset(1)) as i32 - g as i32).abs() as u32
+            + ((*p.offset(2)) as i32 - b as i32).abs() as u32
+        };
+        if dist < best_dist {
+            best = i as u32;
+            best_dist = dist;
+        }
+    }
+    best
+}
+
+impl FbCon {
+    fn put_pixel(&self, x : usize, y : usize, value : u32) {
+        unsafe {
+            let p = self.base.offset((y * self.pitch + x * self.bytes) as isize);
+            match self.bytes {
+                1 => *p = value as u8,
+                2 => *(p as *mut u16) = value as u16,
+                3 => {
+                    *p = value as u8;
+                    *p.offset(1) = (value >> 8) as u8;
+                    *p.offset(2) = (value >> 16) as u8;
+                },
+                _ => *(p as *mut u32) = value,
+            }
+        }
+    }
+
+    fn draw(&self, col : usize, row : usize, c : u8) {
+        let glyph = if c >= 32 && c < 127 { &font[(c - 32) as usize] } else { &font[0] };
+        for y in 0..glyph_height {
+            let bits = glyph[y];
+            for x in 0..glyph_width {
+                let value = if bits & (1 << x) != 0 { self.fg } else { self.bg };
+                self.put_pixel(col * glyph_width + x, row * glyph_height + y, value);
+            }
+        }
+    }
+
+    fn clear_rows(&self, start : usize, end : usize) {
+        for row in start..end {
+            for col in 0..self.cols {
+                self.draw(col, row, b' ');
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.clear_rows(0, self.rows);
+        self.col = 0;
+        self.row = 0;
+    }
+
+    fn scroll(&mut self) {
+        let line = self.pitch * glyph_height;
+        unsafe {
+            con::copy_memory(self.base, self.base.offset(line as isize) as *const u8,
+                line * (self.rows - 1));
+        }
+        self.clear_rows(self.rows - 1, self.rows);
+        self.row = self.rows - 1;
+    }
+}
+
+impl Writer for FbCon {
+    fn putc(&mut self, c : char) {
+        match c {
+            '\n' => self.col = self.cols,
+            '\r' => self.col = 0,
+            '\t' => self.col = min((self.col + 8) & !7, self.cols),
+            '\x08' => if self.col > 0 {
+                self.col -= 1;
+            },
+            _ => {
+                self.draw(self.col, self.row, c as u8);
+                self.col += 1;
+            },
+        }
+        if self.col >= self.cols {
+            self.col = 0;
+            self.row += 1;
+        }
+        if self.row >= self.rows {
+            self.scroll();
+        }
+    }
+}
+
+// Switch the console to the boot loader's framebuffer, if it's a graphical
+// one. Needs the frame allocator for mapping the framebuffer.
+pub fn init(info : &boot::Info) {
+    let fb = match info.fb {
+        Some(ref fb) if fb.fbtype != mboot::FBType::Text as u8 => fb,
+        _ => return,
+    };
+    let bytes = (fb.bpp as usize + 7) / 8;
+    if bytes == 0 || bytes > 4 || fb.width < glyph_width as u32 || fb.height < glyph_height as u32 {
+        return;
+    }
+    let c = unsafe { &mut fbcon };
+    c.base = aspace::map_kernel_phys(fb.addr, fb.size());
+    c.pitch = fb.pitch as usize;
+    c.bytes = bytes;
+    c.cols = fb.width as usize / glyph_width;
+    c.rows = fb.height as usize / glyph_height;
+    c.fg = pixel(fb, 0xaa, 0xaa, 0xaa);
+    c.bg = pixel(fb, 0, 0, 0);
+    c.clear();
+    con::get().set_fb(c);
+}
@@ -0,0 +1,612 @@
+// GDB remote serial protocol stub, on a serial port of its own (gdb=com2 on
+// the command line). The kernel stops in the stub on breakpoints and single
+// steps, both in user processes and in the kernel, and when gdb sends a break
+// (Ctrl-C). While stopped, gdb sees the kernel as thread 1 and each process
+// as a thread with id Process::id + 1.
+//
+// Software breakpoints are plain memory writes of int3 from gdb's side, so
+// only the register, memory, step and thread commands are implemented.
+use core::cmp::min;
+use core::intrinsics::copy_nonoverlapping;
+
+use alloc;
+use cmdline;
+use cpu;
+use debug;
+use process;
+use process::Process;
+use serial;
+use start32::kernel_base;
+use util::breakpoint;
+use x86::idt::KernelFrame;
+use x86::seg;
+
+macro_rules! try_opt {
+    ($e:expr) => (try_opt!($e, None));
+    ($e:expr, $r:expr) => (match $e { Some(x) => x, None => return $r });
+}
+
+const max_packet : usize = 1024;
+const kernel_thread : u32 = 1;
+const SIGTRAP : u8 = 5;
+const TF : u64 = 0x100;
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct State {
+    port : u16,
+    packet : [u8; max_packet],
+    out : [u8; max_packet],
+    out_len : usize,
+    // Thread that stopped
+    stopped : u32,
+    // Thread selected for register and memory access
+    thread : u32,
+    // Kernel registers, when the kernel is stopped
+    frame : *mut KernelFrame,
+}
+
+static mut state : *mut State = 0 as *mut State;
+
+fn get() -> Option<&'static mut State> {
+    unsafe { if state.is_null() { None } else { Some(&mut *state) } }
+}
+
+fn thread_id(p : &Process) -> u32 {
+    p.id + 1
+}
+
+fn process(thread : u32) -> Option<&'static mut Process> {
+    if thread > kernel_thread { process::by_id(thread - 1) } else { None }
+}
+
+fn hex_digit(c : u8) -> Option<u64> {
+    match c {
+        b'0' ..= b'9' => Some((c - b'0') as u64),
+        b'a' ..= b'f' => Some((c - b'a' + 10) as u64),
+        b'A' ..= b'F' => Some((c - b'A' + 10) as u64),
+        _ => None,
+    }
+}
+
+fn parse_hex(s : &[u8]) -> Option<u64> {
+    if s.len() == 0 || s.len() > 16 {
+        return None;
+    }
+    let mut res = 0;
+    for &c in s {
+        res = (res << 4) | try_opt!(hex_digit(c));
+    }
+    Some(res)
+}
+
+// Little-endian value from hex bytes, as in register packets.
+fn parse_le(s : &[u8]) -> Option<u64> {
+    let mut res = 0;
+    for i in 0..min(s.len() / 2, 8) {
+        let b = (try_opt!(hex_digit(s[2 * i])) << 4) | try_opt!(hex_digit(s[2 * i + 1]));
+        res |= b << (8 * i);
+    }
+    Some(res)
+}
+
+// Split at the first sep.
+fn split(s : &[u8], sep : u8) -> (&[u8], &[u8]) {
+    match s.iter().position(|&c| c == sep) {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, &[]),
+    }
+}
+
+// Thread id in an H or T packet, 0 for "any" and -1 for "all".
+fn parse_thread(s : &[u8]) -> Option<u32> {
+    if s == b"-1" {
+        Some(0)
+    } else {
+        parse_hex(s).map(|t| t as u32)
+    }
+}
+
+// Bytes of register n in the g packet, 0 for registers we don't have.
+fn reg_size(n : usize) -> usize {
+    match n {
+        0 ..= 16 => 8,
+        17 ..= 23 => 4,
+        _ => 0,
+    }
+}
+
+const num_regs : usize = 24;
+
+impl State {
+    fn send_byte(&self, b : u8) {
+        serial::send_to(self.port, b);
+    }
+
+    fn recv_byte(&self) -> u8 {
+        loop {
+            match serial::recv_from(self.port) {
+                Some(b) => return b,
+                None => (),
+            }
+        }
+    }
+
+    // Receive a packet into self.packet, acknowledging it. Returns its length.
+    fn recv_packet(&mut self, mut started : bool) -> usize {
+        loop {
+            while !started {
+                started = self.recv_byte() == b'$';
+            }
+            started = false;
+            let mut len = 0;
+            let mut sum = 0u8;
+            loop {
+                let c = self.recv_byte();
+                if c == b'#' {
+                    break;
+                }
+                if len < max_packet {
+                    self.packet[len] = c;
+                    len += 1;
+                }
+                sum = sum.wrapping_add(c);
+            }
+            let check = (hex_digit(self.recv_byte()), hex_digit(self.recv_byte()));
+            match check {
+                (Some(h), Some(l)) if (h << 4 | l) as u8 == sum && len < max_packet => {
+                    self.send_byte(b'+');
+                    return len;
+                },
+                _ => self.send_byte(b'-'),
+            }
+        }
+    }
+
+    // Send self.out as a packet. Returns true if gdb started sending a new
+    // packet instead of acknowledging it, which is as good as an ack.
+    fn send_packet(&mut self) -> bool {
+        loop {
+            self.send_byte(b'$');
+            let mut sum = 0u8;
+            for i in 0..self.out_len {
+                self.send_byte(self.out[i]);
+                sum = sum.wrapping_add(self.out[i]);
+            }
+            self.send_byte(b'#');
+            self.send_byte(b"0123456789abcdef"[(sum >> 4) as usize]);
+            self.send_byte(b"0123456789abcdef"[(sum & 15) as usize]);
+            match self.recv_byte() {
+                b'+' => return false,
+                b'$' => return true,
+                _ => (),
+            }
+        }
+    }
+
+    fn put(&mut self, b : u8) {
+        if self.out_len < max_packet {
+            self.out[self.out_len] = b;
+            self.out_len += 1;
+        }
+    }
+
+    fn put_str(&mut self, s : &[u8]) {
+        for &b in s {
+            self.put(b);
+        }
+    }
+
+    fn put_hex_byte(&mut self, b : u8) {
+        self.put(b"0123456789abcdef"[(b >> 4) as usize]);
+        self.put(b"0123456789abcdef"[(b & 15) as usize]);
+    }
+
+    fn put_hex(&mut self, mut x : u64) {
+        let mut digits = [0u8; 16];
+        let mut n = 0;
+        loop {
+            digits[n] = b"0123456789abcdef"[(x & 15) as usize];
+            n += 1;
+            x >>= 4;
+            if x == 0 {
+                break;
+            }
+        }
+        while n > 0 {
+            n -= 1;
+            self.put(digits[n]);
+        }
+    }
+
+    fn put_le(&mut self, x : u64, bytes : usize) {
+        for i in 0..bytes {
+            self.put_hex_byte((x >> (8 * i)) as u8);
+        }
+    }
+
+    fn frame<'a>(&self) -> Option<&'a mut KernelFrame> {
+        if self.frame.is_null() { None } else { Some(unsafe { &mut *self.frame }) }
+    }
+
+    // Pointer to register n of the selected thread, None if it doesn't have
+    // it (e.g. the kernel when it isn't stopped, or the segment registers).
+    fn reg<'a>(&self, n : usize) -> Option<&'a mut u64> {
+        let thread = if self.thread == 0 { self.stopped } else { self.thread };
+        if thread == kernel_thread {
+            let f = try_opt!(self.frame());
+            return Some(match n {
+                0 => &mut f.rax, 1 => &mut f.rbx, 2 => &mut f.rcx, 3 => &mut f.rdx,
+                4 => &mut f.rsi, 5 => &mut f.rdi, 6 => &mut f.rbp, 7 => &mut f.rsp,
+                8 => &mut f.r8, 9 => &mut f.r9, 10 => &mut f.r10, 11 => &mut f.r11,
+                12 => &mut f.r12, 13 => &mut f.r13, 14 => &mut f.r14, 15 => &mut f.r15,
+                16 => &mut f.rip, 17 => &mut f.rflags, 18 => &mut f.cs, 19 => &mut f.ss,
+                _ => return None,
+            });
+        }
+        let p = try_opt!(process(thread));
+        if n == 16 {
+            return Some(&mut p.rip);
+        } else if n == 17 {
+            return Some(&mut p.rflags);
+        }
+        let r = p.regs();
+        Some(match n {
+            0 => &mut r.rax, 1 => &mut r.rbx, 2 => &mut r.rcx, 3 => &mut r.rdx,
+            4 => &mut r.rsi, 5 => &mut r.rdi, 6 => &mut r.rbp, 7 => &mut r.rsp,
+            8 => &mut r.r8, 9 => &mut r.r9, 10 => &mut r.r10, 11 => &mut r.r11,
+            12 => &mut r.r12, 13 => &mut r.r13, 14 => &mut r.r14, 15 => &mut r.r15,
+            _ => return None,
+        })
+    }
+
+    fn reg_value(&self, n : usize) -> u64 {
+        match self.reg(n) {
+            Some(r) => *r,
+            // Segment registers of processes
+            None if n == 18 => seg::user_cs as u64,
+            None if n == 19 => seg::user_ds as u64,
+            None => 0,
+        }
+    }
+
+    // Copy memory of the selected thread to or from buf. Kernel addresses are
+    // read through the kernel's direct mapping, user addresses through the
+    // process' (or for the kernel, the current process') address space.
+    fn memory(&self, addr : u64, buf : &mut [u8], write : bool) -> bool {
+        let len = buf.len() as u64;
+        if addr >= kernel_base && addr.checked_add(len).is_some() {
+            unsafe {
+                if write {
+                    copy_nonoverlapping(buf.as_ptr(), addr as *mut u8, buf.len());
+                } else {
+                    copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len());
+                }
+            }
+            return true;
+        }
+        let thread = if self.thread == 0 { self.stopped } else { self.thread };
+        let p = match process(thread) {
+            Some(p) => p,
+            None => match cpu().process {
+                Some(ref mut p) => &mut **p,
+                None => return false,
+            },
+        };
+        if write {
+            p.aspace().debug_write(addr, buf)
+        } else {
+            p.aspace().copy_from_user(addr, buf)
+        }
+    }
+
+    fn stop_reply(&mut self) {
+        self.put(b'T');
+        self.put_hex_byte(SIGTRAP);
+        self.put_str(b"thread:");
+        let t = self.stopped;
+        self.put_hex(t as u64);
+        self.put(b';');
+    }
+
+    fn read_registers(&mut self) {
+        for n in 0..num_regs {
+            let v = self.reg_value(n);
+            self.put_le(v, reg_size(n));
+        }
+    }
+
+    fn write_registers(&mut self, mut data : &[u8]) -> bool {
+        for n in 0..num_regs {
+            let size = 2 * reg_size(n);
+            if data.len() < size {
+                break;
+            }
+            match (self.reg(n), parse_le(&data[..size])) {
+                (Some(r), Some(v)) => *r = v,
+                (_, None) => return false,
+                _ => (),
+            }
+            data = &data[size..];
+        }
+        true
+    }
+
+    fn read_memory(&mut self, addr : u64, len : usize) -> bool {
+        let mut buf = [0u8; 64];
+        let mut done = 0;
+        // Each byte is two hex digits in the reply
+        let len = min(len, max_packet / 2);
+        while done < len {
+            let n = min(len - done, buf.len());
+            if !self.memory(addr + done as u64, &mut buf[..n], false) {
+                return done > 0;
+            }
+            for i in 0..n {
+                self.put_hex_byte(buf[i]);
+            }
+            done += n;
+        }
+        true
+    }
+
+    fn write_memory(&mut self, addr : u64, data : &[u8]) -> bool {
+        let mut buf = [0u8; 64];
+        let mut done = 0;
+        while done < data.len() / 2 {
+            let n = min(data.len() / 2 - done, buf.len());
+            for i in 0..n {
+                buf[i] = try_opt!(parse_le(&data[2 * (done + i)..2 * (done + i) + 2]), false) as u8;
+            }
+            if !self.memory(addr + done as u64, &mut buf[..n], true) {
+                return false;
+            }
+            done += n;
+        }
+        true
+    }
+
+    fn thread_alive(&self, thread : u32) -> bool {
+        thread == kernel_thread || process(thread).is_some()
+    }
+
+    fn query(&mut self, q : &[u8]) {
+        let (name, args) = split(q, b',');
+        let (name, _) = split(name, b':');
+        match name {
+            b"qSupported" => self.put_str(b"PacketSize=400"),
+            b"qAttached" => self.put(b'1'),
+            b"qC" => {
+                self.put_str(b"QC");
+                let t = self.stopped;
+                self.put_hex(t as u64);
+            },
+            b"qfThreadInfo" => {
+                self.put(b'm');
+                self.put_hex(kernel_thread as u64);
+                for p in process::all() {
+                    self.put(b',');
+                    self.put_hex(thread_id(p) as u64);
+                }
+            },
+            b"qsThreadInfo" => self.put(b'l'),
+            b"qThreadExtraInfo" => {
+                let thread = parse_hex(args).unwrap_or(0) as u32;
+                let info : &[u8] = if thread == kernel_thread {
+                    b"kernel"
+                } else {
+                    match process(thread) {
+                        Some(ref p) if p.is(process::Running) => b"running",
+                        Some(ref p) if p.is(process::Queued) => b"queued",
+                        Some(ref p) if p.is_runnable() => b"stopped",
+                        Some(_) => b"blocked",
+                        None => b"",
+                    }
+                };
+                for &c in info {
+                    self.put_hex_byte(c);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // Handle one command. Returns how to resume for continue and step.
+    fn command(&mut self, len : usize) -> Option<Resume> {
+        let packet = self.packet;
+        let cmd = &packet[..len];
+        if len == 0 {
+            return None;
+        }
+        let args = &cmd[1..];
+        match cmd[0] {
+            b'?' => self.stop_reply(),
+            b'g' => self.read_registers(),
+            b'G' => if self.write_registers(args) {
+                self.put_str(b"OK");
+            } else {
+                self.put_str(b"E01");
+            },
+            b'p' => match parse_hex(args) {
+                Some(n) if (n as usize) < num_regs => {
+                    let v = self.reg_value(n as usize);
+                    self.put_le(v, reg_size(n as usize));
+                },
+                _ => self.put_str(b"E01"),
+            },
+            b'P' => {
+                let (n, v) = split(args, b'=');
+                match (parse_hex(n).and_then(|n| self.reg(n as usize)), parse_le(v)) {
+                    (Some(r), Some(v)) => {
+                        *r = v;
+                        self.put_str(b"OK");
+                    },
+                    _ => self.put_str(b"E01"),
+                }
+            },
+            b'm' => {
+                let (addr, len) = split(args, b',');
+                let ok = match (parse_hex(addr), parse_hex(len)) {
+                    (Some(addr), Some(len)) => self.read_memory(addr, len as usize),
+                    _ => false,
+                };
+                if !ok {
+                    self.out_len = 0;
+                    self.put_str(b"E14");
+                }
+            },
+            b'M' => {
+                let (range, data) = split(args, b':');
+                let (addr, len) = split(range, b',');
+                match (parse_hex(addr), parse_hex(len)) {
+                    (Some(addr), Some(len)) if len as usize * 2 == data.len() && self.write_memory(addr, data) => {
+                        self.put_str(b"OK");
+                    },
+                    _ => self.put_str(b"E14"),
+                }
+            },
+            b'c' | b's' => {
+                if let (Some(addr), Some(r)) = (parse_hex(args), self.reg(16)) {
+                    *r = addr;
+                }
+                return Some(if cmd[0] == b'c' { Resume::Continue } else { Resume::Step });
+            },
+            b'H' if len > 2 => match parse_thread(&args[1..]) {
+                Some(t) if t == 0 || self.thread_alive(t) => {
+                    if args[0] == b'g' {
+                        self.thread = t;
+                    }
+                    self.put_str(b"OK");
+                },
+                _ => self.put_str(b"E01"),
+            },
+            b'T' => match parse_thread(args) {
+                Some(t) if self.thread_alive(t) => self.put_str(b"OK"),
+                _ => self.put_str(b"E01"),
+            },
+            b'q' => self.query(cmd),
+            b'D' => {
+                self.put_str(b"OK");
+                self.send_packet();
+                return Some(Resume::Continue);
+            },
+            b'k' => return Some(Resume::Continue),
+            _ => (),
+        }
+        None
+    }
+
+    // Talk to gdb until it says to continue or step.
+    fn run(&mut self, stopped : u32) -> Resume {
+        self.stopped = stopped;
+        self.thread = 0;
+        self.out_len = 0;
+        self.stop_reply();
+        let mut started = self.send_packet();
+        loop {
+            let len = self.recv_packet(started);
+            self.out_len = 0;
+            match self.command(len) {
+                Some(resume) => return resume,
+                None => started = self.send_packet(),
+            }
+        }
+    }
+}
+
+fn set_step(rflags : &mut u64, resume : Resume) {
+    match resume {
+        Resume::Continue => *rflags &= !TF,
+        Resume::Step => *rflags |= TF,
+    }
+}
+
+fn trap_name(vec : u8) -> &'static str {
+    if vec == 1 { "debug trap" } else { "breakpoint" }
+}
+
+// #DB or #BP in process p. A debugger process attached to p gets it before
+// the stub does.
+pub fn user_trap(p : &mut Process, vec : u8) -> ! {
+    if p.debugger().is_some() {
+        debug::trap(p, vec);
+    }
+    let st = match get() {
+        Some(st) => st,
+        None => {
+            // Nothing to handle it. Leave the process stopped, so that a
+            // debugger can still attach and look at it.
+            kwarn!("process {}: {} at {:#x}, stopped", p.id, trap_name(vec), p.rip);
+            p.set(process::Stopped);
+            p.trap = vec;
+            unsafe { cpu().run(); }
+        },
+    };
+    let resume = st.run(thread_id(p));
+    set_step(&mut p.rflags, resume);
+    unsafe { cpu().switch_to(p); }
+}
+
+// #DB or #BP in the kernel. Returns to the trapping code.
+#[no_mangle]
+pub extern "C" fn kernel_debug_trap(frame : &mut KernelFrame) {
+    let st = match get() {
+        Some(st) => st,
+        None => {
+            kwarn!("kernel {} at {:#x}", trap_name(frame.vector as u8), frame.rip);
+            frame.rflags &= !TF;
+            return;
+        },
+    };
+    st.frame = frame;
+    let resume = st.run(kernel_thread);
+    st.frame = 0 as *mut KernelFrame;
+    set_step(&mut frame.rflags, resume);
+}
+
+// Set up the stub on the port from the command line, if any. Needs the frame
+// allocator.
+pub fn init() {
+    let port = cmdline::get().gdb_port;
+    if port == 0 {
+        return;
+    }
+    if port == serial::port() {
+        kwarn!("gdb: port {:#x} is used by the console", port);
+        return;
+    }
+    if !serial::setup(port, 115200) {
+        kwarn!("gdb: no UART at {:#x}", port);
+        return;
+    }
+    let st = alloc::<State>();
+    st.port = port;
+    unsafe { state = st; }
+    serial::enable_input_irq(port);
+    kinfo!("gdb: stub on port {:#x}", port);
+}
+
+// Stop in the debugger if gdb has sent a break.
+pub fn poll() {
+    match get() {
+        Some(st) => while let Some(b) = serial::recv_from(st.port) {
+            if b == 3 {
+                breakpoint();
+                break;
+            }
+        },
+        None => (),
+    }
+}
+
+// Stop in the debugger, e.g. from the kernel monitor.
+pub fn enter() -> bool {
+    if get().is_none() {
+        return false;
+    }
+    breakpoint();
+    true
+}
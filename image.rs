@@ -0,0 +1,167 @@
+// The contents of a boot module, either in place in physical memory or in
+// frames allocated by the kernel (e.g. when decompressed).
+use core::cmp::min;
+use core::intrinsics::copy_nonoverlapping;
+use core::mem;
+use core::mem::size_of;
+use core::slice;
+
+use alloc;
+use free;
+use start32::PhysAddr;
+use util::abort;
+
+type Table = [*mut u8; 512];
+
+// Up to 512 * 512 pages of data, appended to a byte at a time.
+pub struct FrameList {
+    root : *mut Table,
+    // The page that the next byte goes into
+    last : *mut u8,
+    len : u64,
+}
+
+impl FrameList {
+    pub fn new() -> FrameList {
+        FrameList { root : alloc::<Table>(), last : 0 as *mut u8, len : 0 }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn leaf(&self, index : u64) -> *mut Table {
+        unsafe { (*self.root)[(index >> 9) as usize] as *mut Table }
+    }
+
+    fn page(&self, index : u64) -> *mut u8 {
+        unsafe { (*self.leaf(index))[(index & 511) as usize] }
+    }
+
+    fn add_page(&mut self) {
+        let index = self.len >> 12;
+        if index >= 512 * 512 {
+            abort("Image too large");
+        }
+        if index & 511 == 0 {
+            unsafe { (*self.root)[(index >> 9) as usize] = alloc::<Table>() as *mut Table as *mut u8; }
+        }
+        self.last = alloc::<[u8; 4096]>() as *mut [u8; 4096] as *mut u8;
+        unsafe { (*self.leaf(index))[(index & 511) as usize] = self.last; }
+    }
+
+    pub fn push(&mut self, b : u8) {
+        let offset = self.len & 0xfff;
+        if offset == 0 {
+            self.add_page();
+        }
+        unsafe { *self.last.offset(offset as isize) = b; }
+        self.len += 1;
+    }
+
+    pub fn get(&self, offset : u64) -> u8 {
+        unsafe { *self.page(offset >> 12).offset((offset & 0xfff) as isize) }
+    }
+
+    // The data in the page that holds offset, starting at offset.
+    pub fn chunk<'a>(&self, offset : u64) -> &'a [u8] {
+        let n = min(self.len - offset, 0x1000 - (offset & 0xfff));
+        unsafe { slice::from_raw_parts(self.page(offset >> 12).offset((offset & 0xfff) as isize), n as usize) }
+    }
+
+    pub fn free(&mut self) {
+        let pages = (self.len + 0xfff) >> 12;
+        for i in 0..pages {
+            free(self.page(i));
+            if i & 511 == 511 || i + 1 == pages {
+                free(self.leaf(i));
+            }
+        }
+        free(self.root);
+        self.root = 0 as *mut Table;
+        self.len = 0;
+    }
+}
+
+pub enum Image {
+    Phys(u64, u64),
+    Frames(FrameList),
+}
+
+impl Image {
+    pub fn module(start : u64, end : u64) -> Image {
+        Image::Phys(start, end - start)
+    }
+
+    pub fn len(&self) -> u64 {
+        match *self {
+            Image::Phys(_, size) => size,
+            Image::Frames(ref f) => f.len(),
+        }
+    }
+
+    // The physical address of the image, if it's contiguous in memory.
+    pub fn paddr(&self) -> Option<u64> {
+        match *self {
+            Image::Phys(paddr, _) => Some(paddr),
+            Image::Frames(_) => None,
+        }
+    }
+
+    // The contiguous part of the image that starts at offset.
+    pub fn chunk<'a>(&self, offset : u64) -> &'a [u8] {
+        match *self {
+            Image::Phys(paddr, size) => unsafe {
+                slice::from_raw_parts(PhysAddr(paddr + offset), (size - offset) as usize)
+            },
+            Image::Frames(ref f) => f.chunk(offset),
+        }
+    }
+
+    // Copy n bytes at offset into dst. The range must be inside the image.
+    pub fn copy(&self, mut offset : u64, mut dst : *mut u8, mut n : u64) {
+        while n > 0 {
+            let src = self.chunk(offset);
+            let m = min(n, src.len() as u64);
+            unsafe {
+                copy_nonoverlapping(src.as_ptr(), dst, m as usize);
+                dst = dst.offset(m as isize);
+            }
+            offset += m;
+            n -= m;
+        }
+    }
+
+    pub fn read<T : Copy>(&self, offset : u64) -> Option<T> {
+        let size = size_of::<T>() as u64;
+        if offset > self.len() || self.len() - offset < size {
+            return None;
+        }
+        unsafe {
+            let mut res : T = mem::zeroed();
+            self.copy(offset, &mut res as *mut T as *mut u8, size);
+            Some(res)
+        }
+    }
+
+    pub fn starts_with(&self, magic : &[u8]) -> bool {
+        let n = magic.len() as u64;
+        if self.len() < n {
+            return false;
+        }
+        for i in 0..n {
+            if self.chunk(i)[0] != magic[i as usize] {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Free the kernel's copy of the image, if any.
+    pub fn free(&mut self) {
+        match *self {
+            Image::Frames(ref mut f) => f.free(),
+            Image::Phys(_, _) => (),
+        }
+    }
+}
@@ -0,0 +1,405 @@
+// Decompression of gzip and zlib compressed boot modules. The deflate decoder
+// follows zlib's contrib/puff: slow but small, which matters more here.
+use alloc;
+use free;
+use image::FrameList;
+use image::Image;
+
+const max_bits : usize = 15;
+const max_lcodes : usize = 286;
+const max_dcodes : usize = 30;
+const fix_lcodes : usize = 288;
+
+static lbase : [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+static lext : [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+static dbase : [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577];
+static dext : [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order of the code length code lengths in a dynamic block header
+static clorder : [u8; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// CRC-32 four bits at a time
+static crc_table : [u32; 16] = [
+    0x00000000, 0x1db71064, 0x3b6e20c8, 0x26d930ac,
+    0x76dc4190, 0x6b6b51f4, 0x4db26158, 0x5005713c,
+    0xedb88320, 0xf00f9344, 0xd6d6a3e8, 0xcb61b38c,
+    0x9b64c2b0, 0x86d3d2d4, 0xa00ae278, 0xbdbdf21c];
+
+type Result<T> = ::core::result::Result<T, &'static str>;
+
+// Canonical Huffman code: the number of codes of each length, and the symbols
+// ordered by code.
+struct Huffman {
+    count : [u16; max_bits + 1],
+    symbol : [u16; fix_lcodes],
+}
+
+impl Huffman {
+    // Returns 0 for a complete code, positive for an incomplete code and
+    // negative for an over-subscribed (invalid) code.
+    fn construct(&mut self, lengths : &[u16]) -> i32 {
+        for c in self.count.iter_mut() {
+            *c = 0;
+        }
+        for &l in lengths {
+            self.count[l as usize] += 1;
+        }
+        if self.count[0] as usize == lengths.len() {
+            return 0;
+        }
+        let mut left : i32 = 1;
+        for len in 1..max_bits + 1 {
+            left <<= 1;
+            left -= self.count[len] as i32;
+            if left < 0 {
+                return left;
+            }
+        }
+        let mut offs = [0u16; max_bits + 1];
+        for len in 1..max_bits {
+            offs[len + 1] = offs[len] + self.count[len];
+        }
+        for (sym, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                self.symbol[offs[l as usize] as usize] = sym as u16;
+                offs[l as usize] += 1;
+            }
+        }
+        left
+    }
+}
+
+// Allocated in a frame rather than on the stack.
+struct State {
+    lencode : Huffman,
+    distcode : Huffman,
+    lengths : [u16; max_lcodes + max_dcodes],
+    input : &'static [u8],
+    pos : usize,
+    bitbuf : u32,
+    bitcnt : u32,
+}
+
+impl State {
+    fn bits(&mut self, need : u32) -> Result<u32> {
+        let mut val = self.bitbuf;
+        while self.bitcnt < need {
+            if self.pos == self.input.len() {
+                return Err("unexpected end of data");
+            }
+            val |= (self.input[self.pos] as u32) << self.bitcnt;
+            self.pos += 1;
+            self.bitcnt += 8;
+        }
+        self.bitbuf = val >> need;
+        self.bitcnt -= need;
+        Ok(val & ((1 << need) - 1))
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        if self.pos == self.input.len() {
+            return Err("unexpected end of data");
+        }
+        self.pos += 1;
+        Ok(self.input[self.pos - 1])
+    }
+
+    fn stored(&mut self, out : &mut FrameList) -> Result<()> {
+        self.bitbuf = 0;
+        self.bitcnt = 0;
+        let len = try!(self.byte()) as u16 | (try!(self.byte()) as u16) << 8;
+        let nlen = try!(self.byte()) as u16 | (try!(self.byte()) as u16) << 8;
+        if len != !nlen {
+            return Err("bad stored block length");
+        }
+        for _ in 0..len {
+            out.push(try!(self.byte()));
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self, dist : bool) -> Result<u16> {
+        let mut code : i32 = 0;
+        let mut first : i32 = 0;
+        let mut index : i32 = 0;
+        for len in 1..max_bits + 1 {
+            code |= try!(self.bits(1)) as i32;
+            let h = if dist { &self.distcode } else { &self.lencode };
+            let count = h.count[len] as i32;
+            if code - count < first {
+                return Ok(h.symbol[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err("bad code")
+    }
+
+    fn codes(&mut self, out : &mut FrameList) -> Result<()> {
+        loop {
+            let sym = try!(self.decode(false)) as usize;
+            if sym < 256 {
+                out.push(sym as u8);
+            } else if sym == 256 {
+                return Ok(());
+            } else {
+                let sym = sym - 257;
+                if sym >= 29 {
+                    return Err("bad length symbol");
+                }
+                let len = lbase[sym] as u64 + try!(self.bits(lext[sym] as u32)) as u64;
+                let dsym = try!(self.decode(true)) as usize;
+                if dsym >= 30 {
+                    return Err("bad distance symbol");
+                }
+                let dist = dbase[dsym] as u64 + try!(self.bits(dext[dsym] as u32)) as u64;
+                if dist > out.len() {
+                    return Err("distance too far back");
+                }
+                for _ in 0..len {
+                    let b = out.get(out.len() - dist);
+                    out.push(b);
+                }
+            }
+        }
+    }
+
+    fn fixed(&mut self, out : &mut FrameList) -> Result<()> {
+        for sym in 0..fix_lcodes {
+            self.lengths[sym] = match sym {
+                0 ..= 143 => 8,
+                144 ..= 255 => 9,
+                256 ..= 279 => 7,
+                _ => 8,
+            };
+        }
+        self.lencode.construct(&self.lengths[..fix_lcodes]);
+        for sym in 0..max_dcodes {
+            self.lengths[sym] = 5;
+        }
+        self.distcode.construct(&self.lengths[..max_dcodes]);
+        self.codes(out)
+    }
+
+    fn dynamic(&mut self, out : &mut FrameList) -> Result<()> {
+        let nlen = try!(self.bits(5)) as usize + 257;
+        let ndist = try!(self.bits(5)) as usize + 1;
+        let ncode = try!(self.bits(4)) as usize + 4;
+        if nlen > max_lcodes || ndist > max_dcodes {
+            return Err("bad counts");
+        }
+
+        for i in 0..19 {
+            self.lengths[clorder[i] as usize] =
+                if i < ncode { try!(self.bits(3)) as u16 } else { 0 };
+        }
+        if self.lencode.construct(&self.lengths[..19]) != 0 {
+            return Err("incomplete code length code");
+        }
+
+        let mut index = 0;
+        while index < nlen + ndist {
+            let sym = try!(self.decode(false));
+            if sym < 16 {
+                self.lengths[index] = sym;
+                index += 1;
+                continue;
+            }
+            let (len, repeat) = match sym {
+                16 => {
+                    if index == 0 {
+                        return Err("repeat with no first length");
+                    }
+                    (self.lengths[index - 1], 3 + try!(self.bits(2)))
+                },
+                17 => (0, 3 + try!(self.bits(3))),
+                _ => (0, 11 + try!(self.bits(7))),
+            };
+            if index + repeat as usize > nlen + ndist {
+                return Err("too many lengths");
+            }
+            for _ in 0..repeat {
+                self.lengths[index] = len;
+                index += 1;
+            }
+        }
+
+        if self.lengths[256] == 0 {
+            return Err("no end-of-block code");
+        }
+        let err = self.lencode.construct(&self.lengths[..nlen]);
+        if err < 0 || (err > 0 && nlen - self.lencode.count[0] as usize != 1) {
+            return Err("bad literal/length code");
+        }
+        let err = self.distcode.construct(&self.lengths[nlen..nlen + ndist]);
+        if err < 0 || (err > 0 && ndist - self.distcode.count[0] as usize != 1) {
+            return Err("bad distance code");
+        }
+        self.codes(out)
+    }
+
+    fn inflate(&mut self, out : &mut FrameList) -> Result<()> {
+        loop {
+            let last = try!(self.bits(1));
+            match try!(self.bits(2)) {
+                0 => try!(self.stored(out)),
+                1 => try!(self.fixed(out)),
+                2 => try!(self.dynamic(out)),
+                _ => return Err("bad block type"),
+            }
+            if last != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    // Little-endian 32-bit value in the trailer. Trailers start on a byte
+    // boundary.
+    fn trailer32(&mut self) -> Result<u32> {
+        self.bitbuf = 0;
+        self.bitcnt = 0;
+        let mut res = 0;
+        for i in 0..4 {
+            res |= (try!(self.byte()) as u32) << (8 * i);
+        }
+        Ok(res)
+    }
+
+    fn skip_cstr(&mut self) -> Result<()> {
+        while try!(self.byte()) != 0 {}
+        Ok(())
+    }
+
+    fn gzip_header(&mut self) -> Result<()> {
+        const FHCRC : u8 = 2;
+        const FEXTRA : u8 = 4;
+        const FNAME : u8 = 8;
+        const FCOMMENT : u8 = 16;
+
+        // magic (2), method (1), flags (1), mtime (4), xfl (1), os (1)
+        self.pos = 3;
+        let flags = try!(self.byte());
+        self.pos = 10;
+        if flags & FEXTRA != 0 {
+            let xlen = try!(self.byte()) as usize | (try!(self.byte()) as usize) << 8;
+            for _ in 0..xlen {
+                try!(self.byte());
+            }
+        }
+        if flags & FNAME != 0 {
+            try!(self.skip_cstr());
+        }
+        if flags & FCOMMENT != 0 {
+            try!(self.skip_cstr());
+        }
+        if flags & FHCRC != 0 {
+            try!(self.byte());
+            try!(self.byte());
+        }
+        Ok(())
+    }
+}
+
+fn crc32(out : &FrameList) -> u32 {
+    let mut crc = !0u32;
+    let mut offset = 0;
+    while offset < out.len() {
+        let chunk = out.chunk(offset);
+        for &b in chunk {
+            crc ^= b as u32;
+            crc = (crc >> 4) ^ crc_table[(crc & 15) as usize];
+            crc = (crc >> 4) ^ crc_table[(crc & 15) as usize];
+        }
+        offset += chunk.len() as u64;
+    }
+    !crc
+}
+
+fn adler32(out : &FrameList) -> u32 {
+    let mut a : u32 = 1;
+    let mut b : u32 = 0;
+    let mut offset = 0;
+    while offset < out.len() {
+        let chunk = out.chunk(offset);
+        for &x in chunk {
+            a = (a + x as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        offset += chunk.len() as u64;
+    }
+    (b << 16) | a
+}
+
+fn is_gzip(data : &[u8]) -> bool {
+    data.len() >= 10 && data[0] == 0x1f && data[1] == 0x8b && data[2] == 8
+}
+
+fn is_zlib(data : &[u8]) -> bool {
+    // Deflate with a window of at most 32K, and no preset dictionary. The check
+    // is weak (plenty of x86 code passes it), so zlib is only tried for names
+    // ending in ".zz".
+    data.len() >= 2 && data[0] & 0x0f == 8 && data[0] >> 4 <= 7
+        && data[1] & 0x20 == 0 && ((data[0] as u16) << 8 | data[1] as u16) % 31 == 0
+}
+
+// gzip is recognized by its header, zlib only by the name (the first word of
+// the arguments).
+pub fn is_compressed(image : &Image, name : &[u8]) -> bool {
+    match image.paddr() {
+        Some(_) => {
+            let data = image.chunk(0);
+            is_gzip(data) || (name.ends_with(b".zz") && is_zlib(data))
+        },
+        None => false,
+    }
+}
+
+fn run(s : &mut State, out : &mut FrameList) -> Result<()> {
+    if is_gzip(s.input) {
+        try!(s.gzip_header());
+        try!(s.inflate(out));
+        let crc = try!(s.trailer32());
+        let size = try!(s.trailer32());
+        if crc != crc32(out) || size != out.len() as u32 {
+            return Err("gzip checksum or size mismatch");
+        }
+    } else {
+        s.pos = 2;
+        try!(s.inflate(out));
+        let adler = try!(s.trailer32()).swap_bytes();
+        if adler != adler32(out) {
+            return Err("zlib checksum mismatch");
+        }
+    }
+    Ok(())
+}
+
+// Decompress a gzip or zlib compressed image into newly allocated frames.
+pub fn inflate(image : &Image) -> Result<Image> {
+    let s = alloc::<State>();
+    s.input = image.chunk(0);
+    s.pos = 0;
+    s.bitbuf = 0;
+    s.bitcnt = 0;
+    let mut out = FrameList::new();
+    let res = run(s, &mut out);
+    free(s as *mut State);
+    match res {
+        Ok(()) => Ok(Image::Frames(out)),
+        Err(e) => {
+            out.free();
+            Err(e)
+        }
+    }
+}
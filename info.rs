@@ -0,0 +1,166 @@
+// Read-only introspection of kernel objects for user space (the INFO syscall),
+// for ps-, lsof- and free-like tools. Each operation copies an array of one of
+// the records below into the caller's buffer.
+//
+// The records are little-endian with fixed layouts: fields are only ever added
+// at the end, so the record size for a given op doesn't shrink and tools
+// should use the sizes they were built with.
+use core::mem::size_of;
+use core::slice;
+
+use aspace::AddressSpace;
+use cpu;
+use mem;
+use process;
+use process::Process;
+use syscall::err;
+
+pub mod op {
+    // One MemInfo
+    pub const MEM : u64 = 0;
+    // ProcInfo for every process, in creation order
+    pub const PROCS : u64 = 1;
+    // HandleInfo for each handle of process id
+    pub const HANDLES : u64 = 2;
+    // MapCardInfo for each map card of process id
+    pub const MAPCARDS : u64 = 3;
+    // BackingInfo for each page of process id that is backed by memory
+    pub const BACKINGS : u64 = 4;
+    // Not a query: give the Inspect capability to the process at the other
+    // end of handle id
+    pub const GRANT : u64 = 5;
+    // One stats::Counters, for the CPU running the caller
+    pub const STATS : u64 = 6;
+}
+
+#[repr(C)]
+pub struct MemInfo {
+    pub total_pages : u64,
+    pub free_pages : u64,
+    pub used_pages : u64,
+}
+
+#[repr(C)]
+pub struct ProcInfo {
+    pub id : u32,
+    // process::FlagBit bits
+    pub flags : u32,
+    pub rip : u64,
+    pub cr3 : u64,
+    // The fault address (with access flags) when PFault is set
+    pub fault_addr : u64,
+    // Process this one is blocked on, 0 if none
+    pub waiting_for : u32,
+    pub num_handles : u32,
+    pub num_waiters : u32,
+    pub reserved : u32,
+}
+
+#[repr(C)]
+pub struct HandleInfo {
+    pub id : u64,
+    // The process at the other end
+    pub process : u32,
+    pub reserved : u32,
+    // The other end's id for this handle, 0 if it isn't associated yet
+    pub other_id : u64,
+    // Pending pulses
+    pub pulses : u64,
+}
+
+#[repr(C)]
+pub struct MapCardInfo {
+    // The card covers vaddr up to the next card's vaddr
+    pub vaddr : u64,
+    pub handle : u64,
+    // Handle offset minus vaddr (or physical address minus vaddr for Phys
+    // cards), with the aspace::mapflag access flags in the low 12 bits
+    pub offset : u64,
+}
+
+#[repr(C)]
+pub struct BackingInfo {
+    pub vaddr : u64,
+    // aspace::mapflag flags
+    pub flags : u64,
+    pub paddr : u64,
+}
+
+// Copy the records from items into buf, skipping the first start and stopping
+// when buf is full. Returns the total number of records, or EFAULT if buf
+// isn't writable.
+fn copy_out<T, I : Iterator<Item = T>>(aspace : &mut AddressSpace, items : I, start : u64, buf : u64, len : u64) -> u64 {
+    let size = size_of::<T>() as u64;
+    let mut total = 0;
+    for rec in items {
+        let i = total;
+        total += 1;
+        if i < start || (i - start + 1) * size > len {
+            continue;
+        }
+        let bytes = unsafe { slice::from_raw_parts(&rec as *const T as *const u8, size as usize) };
+        if !aspace.copy_to_user(buf + (i - start) * size, bytes) {
+            return err::EFAULT;
+        }
+    }
+    total
+}
+
+fn proc_info(p : &Process) -> ProcInfo {
+    ProcInfo {
+        id : p.id,
+        flags : p.flags() as u32,
+        rip : p.rip,
+        cr3 : p.cr3,
+        fault_addr : p.fault_addr,
+        waiting_for : p.waiting_for().map_or(0, |w| w.id),
+        num_handles : p.handles().count() as u32,
+        num_waiters : p.waiters.iter().count() as u32,
+        reserved : 0,
+    }
+}
+
+// Run op for process id, copying the records after the first start into the
+// len bytes at buf in p. Returns the total number of records, EINVAL for a bad
+// op or process id, or EFAULT if buf isn't writable. Pages of p written to may
+// be faulted in meanwhile, so backings of p itself may be a bit out of date.
+pub fn info(p : &mut Process, op : u64, id : u64, start : u64, buf : u64, len : u64) -> u64 {
+    let aspace = unsafe { &mut *(p.aspace() as *mut AddressSpace) };
+    if op == op::MEM {
+        let m = mem::get();
+        let rec = MemInfo {
+            total_pages : (m.free_pages() + m.used_pages()) as u64,
+            free_pages : m.free_pages() as u64,
+            used_pages : m.used_pages() as u64,
+        };
+        return copy_out(aspace, Some(rec).into_iter(), start, buf, len);
+    } else if op == op::PROCS {
+        return copy_out(aspace, process::all().map(|p| proc_info(p)), start, buf, len);
+    } else if op == op::STATS {
+        return copy_out(aspace, Some(cpu().stats.count).into_iter(), start, buf, len);
+    }
+    let target = match process::by_id(id as u32) {
+        Some(t) if id <= u32::max_value() as u64 => t,
+        _ => return err::EINVAL,
+    };
+    match op {
+        op::HANDLES => copy_out(aspace, target.handles().map(|(id, h)| HandleInfo {
+            id : id,
+            process : h.process().id,
+            reserved : 0,
+            other_id : h.other.map_or(0, |o| unsafe { (*o).id() }),
+            pulses : h.pulses(),
+        }), start, buf, len),
+        op::MAPCARDS => copy_out(aspace, target.aspace().mapcards().map(|(_, c)| MapCardInfo {
+            vaddr : c.vaddr(),
+            handle : c.handle,
+            offset : c.offset,
+        }), start, buf, len),
+        op::BACKINGS => copy_out(aspace, target.aspace().backings().map(|(_, b)| BackingInfo {
+            vaddr : b.vaddr(),
+            flags : b.flags() as u64,
+            paddr : b.paddr(),
+        }), start, buf, len),
+        _ => err::EINVAL,
+    }
+}
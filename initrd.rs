@@ -0,0 +1,143 @@
+// The initial ramdisk: a tar (ustar) archive module with programs that are
+// started on demand rather than at boot. The init process gets the archive
+// mapped read-only and can start programs from it with the NEWPROC syscall.
+use core::slice;
+
+use aspace::AddressSpace;
+use process::Process;
+use start32::PhysAddr;
+
+// Where the archive is mapped in the init process.
+pub const vaddr : u64 = 0x40000000;
+
+const block_size : u64 = 512;
+
+#[derive(Clone, Copy)]
+pub struct Archive {
+    pub paddr : u64,
+    pub size : u64,
+}
+
+static mut archive : Option<Archive> = None;
+
+pub fn get() -> Option<Archive> {
+    unsafe { archive }
+}
+
+pub fn set(a : Archive) {
+    unsafe { archive = Some(a); }
+}
+
+#[derive(Clone, Copy)]
+pub struct File {
+    pub name : &'static [u8],
+    // Offset of the file data from the start of the archive
+    pub offset : u64,
+    pub size : u64,
+}
+
+fn field(header : &'static [u8], start : usize, len : usize) -> &'static [u8] {
+    let f = &header[start..start + len];
+    match f.iter().position(|&c| c == 0) {
+        Some(n) => &f[..n],
+        None => f,
+    }
+}
+
+fn parse_octal(s : &[u8]) -> Option<u64> {
+    let mut res = 0;
+    for &c in s {
+        match c {
+            b'0' ..= b'7' => res = res * 8 + (c - b'0') as u64,
+            b' ' => (),
+            _ => return None,
+        }
+    }
+    Some(res)
+}
+
+// Module name "initrd", or a ustar header at the start of the module.
+pub fn is_archive(name : &[u8], paddr : u64, size : u64) -> bool {
+    if name == b"initrd" {
+        return true;
+    }
+    size >= block_size && field(Archive { paddr : paddr, size : size }.block(0), 257, 5) == b"ustar"
+}
+
+pub struct Files {
+    archive : Archive,
+    pos : u64,
+}
+
+impl Iterator for Files {
+    type Item = File;
+
+    fn next(&mut self) -> Option<File> {
+        while self.pos + block_size <= self.archive.size {
+            let header = self.archive.block(self.pos);
+            let name = field(header, 0, 100);
+            // A zero block marks the end of the archive
+            if name.len() == 0 {
+                return None;
+            }
+            let size = match parse_octal(field(header, 124, 12)) {
+                Some(size) => size,
+                None => return None,
+            };
+            let offset = self.pos + block_size;
+            if size > self.archive.size - offset {
+                return None;
+            }
+            self.pos = offset + ((size + block_size - 1) & !(block_size - 1));
+            match header[156] {
+                b'0' | 0 => (),
+                // Directories, links etc.
+                _ => continue,
+            }
+            let name = if name.starts_with(b"./") { &name[2..] } else { name };
+            return Some(File { name : name, offset : offset, size : size });
+        }
+        None
+    }
+}
+
+impl Archive {
+    fn block(&self, offset : u64) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(PhysAddr(self.paddr + offset), block_size as usize) }
+    }
+
+    pub fn files(&self) -> Files {
+        Files { archive : *self, pos : 0 }
+    }
+
+    pub fn find(&self, name : &[u8]) -> Option<File> {
+        self.files().find(|f| f.name == name)
+    }
+
+    // Whether offset..offset + size is inside the archive.
+    pub fn contains(&self, offset : u64, size : u64) -> bool {
+        offset <= self.size && size <= self.size - offset
+    }
+
+    // Map the archive read-only at vaddr in p. rdx and rcx get the address
+    // and size, following the module arguments in rdi and rsi. Returns false
+    // if p already has something mapped there.
+    pub fn map_into(&self, p : &mut Process) -> bool {
+        use aspace::mapflag::*;
+        let start = self.paddr & !0xfff;
+        let end = (self.paddr + self.size + 0xfff) & !0xfff;
+        {
+            let aspace : &mut AddressSpace = p.aspace();
+            let first = aspace.mapcard_find_def(vaddr);
+            if first.flags() != 0 || first.handle != 0
+                || aspace.mapcard_find_def(vaddr + (end - start) - 1).vaddr() > vaddr {
+                return false;
+            }
+            aspace.mapcard_set(vaddr, 0, start.wrapping_sub(vaddr), Phys | R);
+            aspace.mapcard_end(vaddr + (end - start));
+        }
+        p.regs().rdx = vaddr + (self.paddr & 0xfff);
+        p.regs().rcx = self.size;
+        true
+    }
+}
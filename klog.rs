@@ -0,0 +1,262 @@
+// The kernel log: everything printed on the console is also kept as records
+// in a ring buffer, for a user-space logger to read (the KLOG syscall).
+//
+// The buffer is a header page followed by log_pages data pages, which are
+// mapped read-only and contiguously in the reading process. Each record is a
+// Record followed by its text, padded to 8 bytes. Positions (head, tail) count
+// bytes written since boot; the byte at position n is at data offset
+// n % size, so records may wrap around the end of the data. Records at the
+// tail are overwritten as new ones are added. A reader should copy what it
+// wants and then check that tail hasn't passed it.
+//
+// Until memory is initialized, records go in a small static buffer and are
+// copied to the real one by init.
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::slice;
+
+use aspace::AddressSpace;
+use alloc;
+use cpu;
+use log;
+use process::Process;
+use start32::kernel_base;
+use x86::rdtsc;
+
+const log_pages : usize = 16;
+const early_size : usize = 1024;
+// Longer lines are split into several records
+const max_line : usize = 120;
+
+pub const Magic : u32 = 0x474f4c4b; // "KLOG"
+
+#[repr(C)]
+pub struct Header {
+    pub magic : u32,
+    // Bytes of data after the header page
+    pub size : u32,
+    // Positions of the end of the last record and the start of the first
+    pub head : u64,
+    pub tail : u64,
+    // Sequence number of the next record
+    pub next_seq : u64,
+}
+
+#[repr(C)]
+pub struct Record {
+    pub seq : u64,
+    // Time stamp counter when the record was added
+    pub time : u64,
+    // Length of the text
+    pub len : u32,
+    // log::Level, 0 for plain kprint! output
+    pub level : u32,
+}
+
+struct Log {
+    header : *mut Header,
+    // Data pages, or the early buffer in pages[0]
+    pages : [*mut u8; log_pages],
+    size : usize,
+    // The line being printed, not a record yet
+    line : [u8; max_line],
+    line_len : usize,
+    line_level : log::Level,
+    // Process blocked in KLOG waiting for a new record, or null
+    waiter : *mut Process,
+}
+
+static mut early : [u8; early_size] = [0; early_size];
+static mut early_header : Header = Header { magic : Magic, size : early_size as u32, head : 0, tail : 0, next_seq : 1 };
+static mut klog : Log = Log {
+    header : 0 as *mut Header,
+    pages : [0 as *mut u8; log_pages],
+    size : 0,
+    line : [0; max_line],
+    line_len : 0,
+    line_level : 0,
+    waiter : 0 as *mut Process,
+};
+
+fn get() -> &'static mut Log {
+    unsafe {
+        if klog.header.is_null() {
+            klog.header = &mut early_header;
+            klog.pages[0] = &mut early[0];
+            klog.size = early_size;
+        }
+        &mut klog
+    }
+}
+
+fn round_up(n : u64) -> u64 {
+    (n + 7) & !7
+}
+
+fn byte(pages : &[*mut u8; log_pages], size : usize, pos : u64) -> *mut u8 {
+    let offset = (pos % size as u64) as usize;
+    unsafe { pages[offset / 4096].offset((offset % 4096) as isize) }
+}
+
+impl Log {
+    fn header<'a>(&self) -> &'a mut Header {
+        unsafe { &mut *self.header }
+    }
+
+    fn byte(&self, pos : u64) -> *mut u8 {
+        byte(&self.pages, self.size, pos)
+    }
+
+    fn put(&mut self, pos : u64, data : &[u8]) {
+        for (i, &b) in data.iter().enumerate() {
+            unsafe { *self.byte(pos + i as u64) = b; }
+        }
+    }
+
+    fn get(&self, pos : u64) -> u8 {
+        unsafe { *self.byte(pos) }
+    }
+
+    // Length of the record at pos, including header and padding.
+    fn record_size(&self, pos : u64) -> u64 {
+        let mut len = 0u32;
+        for i in 0..4 {
+            len |= (self.get(pos + 16 + i) as u32) << (8 * i);
+        }
+        round_up(size_of::<Record>() as u64 + len as u64)
+    }
+
+    // Drop records from the tail until n more bytes fit.
+    fn make_room(&mut self, n : u64) {
+        let h = self.header();
+        while h.head + n - h.tail > self.size as u64 {
+            h.tail += self.record_size(h.tail);
+        }
+    }
+
+    fn add(&mut self, level : log::Level, text : &[u8]) {
+        let h = self.header();
+        let record = Record {
+            seq : h.next_seq,
+            time : rdtsc(),
+            len : text.len() as u32,
+            level : level as u32,
+        };
+        let total = round_up((size_of::<Record>() + text.len()) as u64);
+        self.make_room(total);
+        let bytes = unsafe {
+            slice::from_raw_parts(&record as *const Record as *const u8, size_of::<Record>())
+        };
+        self.put(h.head, bytes);
+        self.put(h.head + bytes.len() as u64, text);
+        h.head += total;
+        h.next_seq += 1;
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if !self.waiter.is_null() {
+            let p = unsafe { &mut *self.waiter };
+            self.waiter = null_mut();
+            p.regs().rax = self.header().next_seq;
+            cpu().queue(p);
+        }
+    }
+
+    fn putc(&mut self, c : u8) {
+        if c != b'\n' {
+            self.line[self.line_len] = c;
+            self.line_len += 1;
+        }
+        if c == b'\n' || self.line_len == max_line {
+            // Reset first: waking a waiter may print more.
+            let (level, len) = (self.line_level, self.line_len);
+            let line = self.line;
+            self.line_len = 0;
+            self.line_level = 0;
+            self.add(level, &line[..len]);
+        }
+    }
+}
+
+// Record a character printed on the console.
+pub fn putc(c : char) {
+    get().putc(c as u8);
+}
+
+// Set the level of the line being printed.
+pub fn set_level(level : log::Level) {
+    get().line_level = level;
+}
+
+// Switch from the early buffer to the real one. Needs the frame allocator.
+pub fn init() {
+    let l = get();
+    let old_header = l.header();
+    let (old_pages, old_size) = (l.pages, l.size);
+    l.header = alloc::<Header>();
+    for i in 0..log_pages {
+        l.pages[i] = alloc::<[u8; 4096]>() as *mut [u8; 4096] as *mut u8;
+    }
+    l.size = log_pages * 4096;
+    let h = l.header();
+    h.magic = Magic;
+    h.size = l.size as u32;
+    h.next_seq = old_header.next_seq;
+    for pos in old_header.tail..old_header.head {
+        let b = unsafe { *byte(&old_pages, old_size, pos) };
+        l.put(h.head, &[b]);
+        h.head += 1;
+    }
+}
+
+// Map the log read-only at addr, which must be page aligned and have nothing
+// mapped in the whole range. Returns the size of the mapping, or 0 if addr
+// isn't usable.
+pub fn map(aspace : &mut AddressSpace, addr : u64) -> u64 {
+    use aspace::mapflag::*;
+    let l = get();
+    let size = ((log_pages + 1) * 4096) as u64;
+    if l.size != log_pages * 4096 || addr & 0xfff != 0 || addr == 0
+        || addr.checked_add(size).map_or(true, |e| e > 1 << 47) {
+        return 0;
+    }
+    // The card covering addr must be empty, and no other card may start
+    // inside the range.
+    let first = aspace.mapcard_find_def(addr);
+    if first.flags() != 0 || first.handle != 0
+        || aspace.mapcard_find_def(addr + size - 1).vaddr() > addr {
+        return 0;
+    }
+    let paddr = l.header as u64 - kernel_base;
+    aspace.mapcard_set(addr, 0, paddr.wrapping_sub(addr), Phys | R);
+    for i in 0..log_pages {
+        let vaddr = addr + 4096 * (i as u64 + 1);
+        let paddr = l.pages[i] as u64 - kernel_base;
+        aspace.mapcard_set(vaddr, 0, paddr.wrapping_sub(vaddr), Phys | R);
+    }
+    // Keeps a mapping that starts right after the log
+    aspace.mapcard_end(addr + size);
+    size
+}
+
+// Sequence number of the next record.
+pub fn next_seq() -> u64 {
+    get().header().next_seq
+}
+
+// Whether p is blocked in KLOG waiting for a record.
+pub fn is_waiter(p : &Process) -> bool {
+    get().waiter as *const Process == p as *const Process
+}
+
+// Make p the process to wake up when the next record is added. Its rax gets
+// the new next_seq. Returns false if another process is already waiting.
+pub fn wait(p : &mut Process) -> bool {
+    let l = get();
+    if !l.waiter.is_null() {
+        return false;
+    }
+    l.waiter = p;
+    true
+}
@@ -0,0 +1,363 @@
+// In-kernel self-tests, run at boot instead of the boot modules when selected
+// on the command line: test=all, or test=dict,ipc,... Results are printed on the
+// console (so also on the serial port and debugcon), and if QEMU has an
+// isa-debug-exit device at port 0xf4, QEMU exits with status 1 when all tests
+// passed and 3 when some test failed. See ktest.sh.
+//
+// Most tests run directly. Tests that need processes to run start them and
+// return a check, which runs when the CPU has nothing left to do.
+use core::mem::size_of;
+
+use aspace::AddressSpace;
+use alloc;
+use cmdline;
+use cmdline::split;
+use con::Bytes;
+use cpu;
+use dict::Dict;
+use dict::DictItem;
+use dict::DictNode;
+use dlist::DList;
+use dlist::DListItem;
+use dlist::DListNode;
+use free;
+use mem;
+use process::Process;
+use x86::outb;
+
+const exit_port : u16 = 0xf4;
+
+macro_rules! check {
+    ($e:expr) => (if !$e {
+        kerror!("ktest: line {}: {} failed", line!(), stringify!($e));
+        return Fail;
+    })
+}
+
+enum Status {
+    Pass,
+    Fail,
+    // Passed if the check returns true once everything is idle
+    Wait(fn() -> bool),
+}
+use self::Status::*;
+
+const tests : [(&'static str, fn() -> Status); 5] = [
+    ("dict", test_dict),
+    ("dlist", test_dlist),
+    ("map_range", test_map_range),
+    ("frames", test_frames),
+    ("ipc", test_ipc),
+];
+
+struct State {
+    next : usize,
+    passed : usize,
+    failed : usize,
+    waiting : Option<(&'static str, fn() -> bool)>,
+}
+
+static mut state : State = State { next : 0, passed : 0, failed : 0, waiting : None };
+
+fn get() -> &'static mut State {
+    unsafe { &mut state }
+}
+
+struct TestItem {
+    node : DictNode<u64, TestItem>,
+    list : DListNode<TestItem>,
+}
+
+impl DictItem for TestItem {
+    type Key = u64;
+    fn node<'a>(&'a mut self) -> &'a mut DictNode<u64, TestItem> {
+        &mut self.node
+    }
+}
+
+impl DListItem for TestItem {
+    fn node<'a>(&'a mut self) -> &'a mut DListNode<TestItem> {
+        &mut self.list
+    }
+}
+
+fn new_item(key : u64) -> *mut TestItem {
+    let item = alloc::<TestItem>();
+    item.node.init(key);
+    item
+}
+
+fn key(item : Option<&mut TestItem>) -> Option<u64> {
+    item.map(|i| i.node.key)
+}
+
+fn test_dict() -> Status {
+    let mut d : Dict<TestItem> = Dict::empty();
+    check!(d.find(1).is_none());
+    for &k in [30, 10, 20].iter() {
+        d.insert(new_item(k));
+    }
+    // find gives the greatest key <= the key
+    check!(key(d.find(10)) == Some(10));
+    check!(key(d.find(20)) == Some(20));
+    check!(key(d.find(25)) == Some(20));
+    check!(key(d.find(1000)) == Some(30));
+    check!(d.find(9).is_none());
+    check!(d.iter().count() == 3);
+    d.remove(20);
+    check!(key(d.find(25)) == Some(10));
+    check!(d.iter().count() == 2);
+    d.remove(20);
+    check!(d.iter().count() == 2);
+    while let Some(item) = d.pop() {
+        free(item as *mut TestItem);
+    }
+    check!(d.find(1000).is_none());
+    Pass
+}
+
+fn test_dlist() -> Status {
+    let mut l : DList<TestItem> = DList::empty();
+    check!(l.pop().is_none());
+    let (a, b, c) = (new_item(1), new_item(2), new_item(3));
+    l.append(a);
+    l.append(b);
+    l.append(c);
+    check!(l.iter().map(|i| i.node.key).eq([1, 2, 3].iter().cloned()));
+    l.remove(b);
+    check!(l.iter().map(|i| i.node.key).eq([1, 3].iter().cloned()));
+    // Removed items can go back in
+    l.append(b);
+    check!(l.iter().map(|i| i.node.key).eq([1, 3, 2].iter().cloned()));
+    check!(l.pop() == Some(a));
+    l.remove(b);
+    check!(l.pop() == Some(c));
+    check!(l.pop().is_none());
+    check!(l.iter().next().is_none());
+    for &item in [a, b, c].iter() {
+        free(item);
+    }
+    Pass
+}
+
+fn test_map_range() -> Status {
+    use aspace::mapflag::*;
+    let aspace = unsafe { &mut *AddressSpace::new() };
+    aspace.mapcard_set(0x10000, 1, 0x1000, R);
+    aspace.mapcard_end(0x40000);
+
+    // Splits the card in three
+    aspace.map_range(0x20000, 0x30000, 2, 0x5000 | R as u64);
+    let c = aspace.mapcard_find_def(0x1f000);
+    check!(c.vaddr() == 0x10000 && c.handle == 1);
+    let c = aspace.mapcard_find_def(0x2f000);
+    check!(c.vaddr() == 0x20000 && c.handle == 2 && c.offset == 0x5000 | R as u64);
+    // The rest of the old card keeps its offset
+    let c = aspace.mapcard_find_def(0x30000);
+    check!(c.vaddr() == 0x30000 && c.handle == 1 && c.offset == 0x1000 | R as u64);
+    check!(aspace.mapcard_find_def(0x40000).handle == 0);
+
+    // Covers the middle card and ends inside the last one
+    aspace.map_range(0x18000, 0x38000, 3, 0);
+    check!(aspace.mapcard_find_def(0x17000).handle == 1);
+    let c = aspace.mapcard_find_def(0x30000);
+    check!(c.vaddr() == 0x18000 && c.handle == 3);
+    let c = aspace.mapcard_find_def(0x38000);
+    check!(c.vaddr() == 0x38000 && c.handle == 1);
+    Pass
+}
+
+fn test_frames() -> Status {
+    let m = mem::get();
+    let used = m.used_pages();
+    let mut frames = [0 as *mut u64; 8];
+    for round in 0..2 {
+        for i in 0..frames.len() {
+            frames[i] = match m.alloc_frame() {
+                Some(p) => p as *mut u64,
+                None => return Fail,
+            };
+            let p = frames[i];
+            check!(p as u64 & 0xfff == 0);
+            check!(frames[..i].iter().all(|&q| q != p));
+            // Zeroed, except for the first word that links free frames
+            for j in 1..512 {
+                check!(unsafe { *p.offset(j) } == 0);
+            }
+            // Dirty it, so the second round gets frames that need clearing
+            for j in 0..512 {
+                unsafe { *p.offset(j) = round + 1; }
+            }
+        }
+        check!(m.used_pages() == used + frames.len());
+        for &p in frames.iter() {
+            m.free_frame(p as *mut u8);
+        }
+        check!(m.used_pages() == used);
+    }
+    Pass
+}
+
+// Each process gets a page of code at 1MB and a stack page just below it,
+// where results are stored.
+fn new_test_proc(code : &[u8]) -> &'static mut Process {
+    use aspace::mapflag::*;
+    let aspace = AddressSpace::new();
+    let p = unsafe { &mut *Process::new(aspace) };
+    let a = p.aspace();
+    a.mapcard_set(0x0ff000, 0, 0, Anon | R | W);
+    a.mapcard_set(0x100000, 0, 0, Anon | R | X);
+    a.mapcard_end(0x101000);
+    let page = a.prefill_anon(0x100000);
+    for (i, &b) in code.iter().enumerate() {
+        unsafe { *page.offset(i as isize) = b; }
+    }
+    p.rip = 0x100000;
+    p.regs().rsp = 0x100000;
+    p
+}
+
+fn read_result(p : &mut Process, i : usize) -> u64 {
+    let mut buf = [0u8; 8];
+    if !p.aspace().copy_from_user(0xff000 + (i * size_of::<u64>()) as u64, &mut buf) {
+        return 0;
+    }
+    let mut res = 0;
+    for i in 0..8 {
+        res |= (buf[i] as u64) << (8 * i);
+    }
+    res
+}
+
+// Calls the server through handle 1 with message 0x11 and 0x1234, stores the
+// reply's message and argument, then waits forever.
+const ipc_client : [u8; 41] = [
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0xb8, 0x11, 0x01, 0x00, 0x00, // mov eax, 0x111 (call 0x11)
+    0xbe, 0x34, 0x12, 0x00, 0x00, // mov esi, 0x1234
+    0x0f, 0x05,                   // syscall
+    0x48, 0x89, 0x04, 0x25, 0x00, 0xf0, 0x0f, 0x00, // mov [0xff000], rax
+    0x48, 0x89, 0x34, 0x25, 0x08, 0xf0, 0x0f, 0x00, // mov [0xff008], rsi
+    0x31, 0xc0,                   // xor eax, eax (recv)
+    0x31, 0xff,                   // xor edi, edi (from anyone)
+    0x0f, 0x05,                   // syscall
+    0xeb, 0xfe,                   // jmp $
+];
+
+// Receives a message, stores it and replies with message 0x12 and the
+// argument plus one, then waits forever.
+const ipc_server : [u8; 32] = [
+    0x31, 0xff,                   // xor edi, edi
+    0x31, 0xc0,                   // xor eax, eax (recv)
+    0x0f, 0x05,                   // syscall
+    0x48, 0x89, 0x04, 0x25, 0x00, 0xf0, 0x0f, 0x00, // mov [0xff000], rax
+    0x48, 0xff, 0xc6,             // inc rsi
+    0xb8, 0x12, 0x00, 0x00, 0x00, // mov eax, 0x12 (send 0x12 to rdi)
+    0x0f, 0x05,                   // syscall
+    0x31, 0xc0,                   // xor eax, eax
+    0x31, 0xff,                   // xor edi, edi
+    0x0f, 0x05,                   // syscall
+    0xeb, 0xfe,                   // jmp $
+];
+
+static mut ipc_procs : (u32, u32) = (0, 0);
+
+fn test_ipc() -> Status {
+    let client = new_test_proc(&ipc_client);
+    let server = new_test_proc(&ipc_server);
+    client.assoc_handles(1, server, 1);
+    unsafe { ipc_procs = (client.id, server.id); }
+    // Server first, so the client's call finds it receiving. The other order
+    // is covered by the reply.
+    cpu().queue(server);
+    cpu().queue(client);
+    Wait(check_ipc)
+}
+
+fn check_ipc() -> bool {
+    use process::by_id;
+    let (client, server) = unsafe { ipc_procs };
+    let (client, server) = match (by_id(client), by_id(server)) {
+        (Some(c), Some(s)) => (c, s),
+        _ => return false,
+    };
+    let (got, reply, arg) = (read_result(server, 0), read_result(client, 0), read_result(client, 1));
+    if got != 0x111 || reply != 0x12 || arg != 0x1235 {
+        kerror!("ktest: ipc: server got {:#x}, client got {:#x} {:#x}", got, reply, arg);
+        return false;
+    }
+    true
+}
+
+fn selected(name : &str) -> bool {
+    split(cmdline::get().tests, b',').any(|t| t == b"all" || t == name.as_bytes())
+}
+
+fn report(name : &str, ok : bool) {
+    let s = get();
+    if ok {
+        s.passed += 1;
+        kprintln!("ktest: {}: ok", name);
+    } else {
+        s.failed += 1;
+        kprintln!("ktest: {}: FAILED", name);
+    }
+}
+
+fn finish() -> ! {
+    let s = get();
+    for t in split(cmdline::get().tests, b',') {
+        if t != b"all" && !tests.iter().any(|&(name, _)| t == name.as_bytes()) {
+            kerror!("ktest: no test named {}", Bytes(t));
+            s.failed += 1;
+        }
+    }
+    kprintln!("ktest: {} passed, {} failed", s.passed, s.failed);
+    unsafe { outb(exit_port, if s.failed == 0 { 0 } else { 1 }); }
+    // Not in QEMU (or no isa-debug-exit)
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile"); }
+    }
+}
+
+fn run_tests() -> ! {
+    let s = get();
+    while s.next < tests.len() {
+        let (name, f) = tests[s.next];
+        s.next += 1;
+        if !selected(name) {
+            continue;
+        }
+        match f() {
+            Pass => report(name, true),
+            Fail => report(name, false),
+            Wait(check) => {
+                s.waiting = Some((name, check));
+                unsafe { cpu().run(); }
+            },
+        }
+    }
+    finish();
+}
+
+// Run the tests selected on the command line, if any, instead of starting
+// the boot modules.
+pub fn run() {
+    if cmdline::get().tests.len() > 0 {
+        kprintln!("ktest: running {}", Bytes(cmdline::get().tests));
+        run_tests();
+    }
+}
+
+// Called when the CPU is idle, to finish a test that is waiting for its
+// processes and go on with the rest.
+pub fn idle() {
+    let s = get();
+    match s.waiting.take() {
+        Some((name, check)) => {
+            report(name, check());
+            run_tests();
+        },
+        None => (),
+    }
+}
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Run the kernel's self-tests (see ktest.rs) in QEMU without a display. The
+# tests to run can be given in TESTS, e.g. TESTS=dict,ipc ./ktest.sh. Exits
+# with status 0 if all tests passed.
+
+qemu-system-x86_64 -cpu SandyBridge -m 32M -display none -serial stdio \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -kernel out/kernel -append "kernel test=${TESTS:-all}" "$@"
+# isa-debug-exit exits with (value << 1) | 1, the kernel writes 0 for success
+[ $? -eq 1 ]
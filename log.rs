@@ -0,0 +1,121 @@
+// Log categories that can be switched on and off at runtime, through the
+// kernel command line (log=ipc,pfault,switch) or the LOGCTL syscall, and the
+// message level for kerror!, kwarn!, kinfo! and kdebug! (loglevel=).
+
+pub type Mask = u64;
+
+pub const ASSOC_PROCS : Mask = 1 << 0;
+pub const PAGE_FAULT : Mask = 1 << 1;
+pub const SWITCH : Mask = 1 << 2;
+pub const QUEUE : Mask = 1 << 3;
+pub const IRQ : Mask = 1 << 4;
+pub const IDLE : Mask = 1 << 5;
+pub const ALLOC : Mask = 1 << 6;
+pub const MEMORY_MAP : Mask = 1 << 7;
+pub const MEMTEST : Mask = 1 << 8;
+pub const RESERVE : Mask = 1 << 9;
+pub const ADD_PTE : Mask = 1 << 10;
+pub const SYSCALL : Mask = 1 << 11;
+pub const UNKNOWN_SYSCALL : Mask = 1 << 12;
+pub const TRANSFER_MESSAGE : Mask = 1 << 13;
+pub const PORTIO : Mask = 1 << 14;
+pub const HMOD : Mask = 1 << 15;
+pub const MAP : Mask = 1 << 16;
+pub const PFAULT : Mask = 1 << 17;
+pub const GRANT : Mask = 1 << 18;
+pub const RECV : Mask = 1 << 19;
+pub const IPC : Mask = 1 << 20;
+pub const PULSE : Mask = 1 << 21;
+
+pub const ALL : Mask = (1 << 22) - 1;
+
+pub static names : [(&'static str, Mask); 23] = [
+    ("assoc", ASSOC_PROCS),
+    ("pagefault", PAGE_FAULT),
+    ("switch", SWITCH),
+    ("queue", QUEUE),
+    ("irq", IRQ),
+    ("idle", IDLE),
+    ("alloc", ALLOC),
+    ("mmap", MEMORY_MAP),
+    ("memtest", MEMTEST),
+    ("reserve", RESERVE),
+    ("pte", ADD_PTE),
+    ("syscall", SYSCALL),
+    ("unknown", UNKNOWN_SYSCALL),
+    ("transfer", TRANSFER_MESSAGE),
+    ("portio", PORTIO),
+    ("hmod", HMOD),
+    ("map", MAP),
+    ("pfault", PFAULT),
+    ("grant", GRANT),
+    ("recv", RECV),
+    ("ipc", IPC),
+    ("pulse", PULSE),
+    ("all", ALL),
+];
+
+static mut mask : Mask = 0;
+
+#[inline(always)]
+pub fn on(m : Mask) -> bool {
+    unsafe { mask & m != 0 }
+}
+
+pub fn get() -> Mask {
+    unsafe { mask }
+}
+
+pub fn set(m : Mask) {
+    unsafe { mask = m; }
+}
+
+// Look up a category by name, 0 if there's no such category.
+pub fn by_name(name : &[u8]) -> Mask {
+    for &(n, m) in names.iter() {
+        if n.as_bytes() == name {
+            return m;
+        }
+    }
+    0
+}
+
+pub type Level = u8;
+
+pub const Error : Level = 1;
+pub const Warn : Level = 2;
+pub const Info : Level = 3;
+pub const Debug : Level = 4;
+
+pub static level_names : [(&'static str, Level); 4] = [
+    ("error", Error),
+    ("warn", Warn),
+    ("info", Info),
+    ("debug", Debug),
+];
+
+// Messages above this level are not printed
+static mut level : Level = Info;
+
+#[inline(always)]
+pub fn level_on(l : Level) -> bool {
+    unsafe { l <= level }
+}
+
+pub fn get_level() -> Level {
+    unsafe { level }
+}
+
+pub fn set_level(l : Level) {
+    unsafe { level = l; }
+}
+
+// Look up a level by name, None if there's no such level.
+pub fn level_by_name(name : &[u8]) -> Option<Level> {
+    for &(n, l) in level_names.iter() {
+        if n.as_bytes() == name {
+            return Some(l);
+        }
+    }
+    None
+}
@@ -8,79 +8,79 @@
 
 #![no_std]
 
+use core::cmp::min;
+use core::intrinsics::copy_nonoverlapping;
 use core::mem::transmute;
 use core::mem::size_of;
+use core::ptr::null_mut;
 
 use aspace::AddressSpace;
-use con::write;
+use con::Bytes;
 use dlist::DList;
+use image::Image;
 use process::Process;
-use start32::PhysAddr;
 use start32::MutPhysAddr;
+use topology::Topology;
 use util::abort;
 use x86::idt;
 pub use x86::idt::irq_entry;
 pub use syscall::syscall;
 
-mod aspace;
+// First, so the other modules get the printing macros.
+#[macro_use]
 #[allow(dead_code)]
 mod con;
+mod aspace;
+mod backtrace;
+mod boot;
+mod bootinfo;
+mod cmdline;
+mod crash;
+mod debug;
 mod dict;
 mod dlist;
+mod elf;
+mod events;
+mod fbcon;
+mod gdb;
+mod image;
+mod info;
+mod inflate;
+mod initrd;
+mod klog;
+mod ktest;
+mod log;
 #[allow(dead_code)]
 mod mboot;
+#[allow(dead_code)]
+mod mboot2;
 mod mem;
+mod monitor;
 mod process;
+mod serial;
 mod start32;
+mod stats;
 mod syscall;
+mod topology;
 pub mod util;
 mod x86;
 
-static log_assoc_procs : bool = false;
-static log_page_fault : bool = false;
-static log_switch : bool = false;
-static log_queue : bool = false;
-static log_irq : bool = false;
-static log_idle : bool = false;
-
-static mem_test : bool = false;
-
-#[allow(dead_code)]
-fn writeMBInfo(info : &mboot::Info) {
-    con::write("Multiboot info at ");
-    con::writePtr(info as *const mboot::Info);
-    con::putc('\n');
-
-    con::write("Flags: ");
-    con::writeHex(info.flags);
-    con::newline();
-
-    if info.has(mboot::MemorySize) {
-        con::writeUInt(info.mem_lower);
-        con::write("kB lower memory, ");
-        con::writeUInt(info.mem_upper);
-        con::write("kB upper memory, ");
-        con::writeUInt((info.mem_lower + info.mem_upper + 1023) / 1024);
-        con::write("MB total.\n");
-    }
-    if info.has(mboot::CommandLine) {
-        let cmdline : *const u8 = PhysAddr(info.cmdline as u64);
-        con::write("Command line @");
-        con::writePtr(cmdline);
-        con::write(" (");
-        con::writeHex(info.cmdline);
-        con::write(") \"");
-        con::writeCStr(cmdline);
-        con::write("\"\n");
-    }
-}
+// Module arguments are copied onto the initial stack, leave most of it for the
+// process.
+pub const max_args_len : usize = 2048;
 
 #[inline(never)]
 pub fn generic_irq_handler(vec : u8) {
-    if log_irq {
-        write("IRQ! vec=");
-        con::writeUInt(vec);
-        con::newline();
+    monitor::poll();
+    gdb::poll();
+    if log::on(log::IRQ) {
+        kprintln!("IRQ! vec={}", vec);
+    }
+    if serial::is_input_irq(vec) {
+        // Input for the monitor or the GDB stub, polled above
+        cpu().stats.irq(vec);
+        serial::end_input_irq();
+        return;
     }
     // 32..47 are IRQ interrupts, 48 is APIC, others are unused.
     if vec > 48 {
@@ -88,9 +88,11 @@ pub fn generic_irq_handler(vec : u8) {
     }
 
     let c = cpu();
+    c.stats.irq(vec);
+    c.events.record(events::kind::IRQ, 0, vec as u64, 0);
     let mask = 1 << (vec - 32);
     if c.irq_delayed & mask != 0 {
-        write("IRQ: already delayed\n");
+        kdebug!("IRQ: already delayed");
         return;
     }
     c.irq_delayed |= mask;
@@ -99,10 +101,8 @@ pub fn generic_irq_handler(vec : u8) {
     }
     let p = c.irq_process().unwrap();
 
-    if log_irq {
-        write("IRQ: proc=");
-        con::writeMutPtr(p);
-        con::newline();
+    if log::on(log::IRQ) {
+        kprintln!("IRQ: proc={:p}", p);
     }
 
     syscall::try_deliver_irq(p);
@@ -119,16 +119,9 @@ pub fn page_fault(p : &mut Process, error : u64) -> ! {
         pub static INSTR : u64 = 16;
     }
 
-    if log_page_fault {
-        write("page fault ");
-        con::writeHex(error);
-        write(" cr2=");
-        con::writePHex(x86::cr2());
-        write(" rip=");
-        con::writePHex(p.rip);
-        write(" in process ");
-        con::writeMutPtr(p);
-        con::newline();
+    if log::on(log::PAGE_FAULT) {
+        kprintln!("page fault {:#x} cr2={:#018x} rip={:#018x} in process {:p}",
+            error, x86::cr2(), p.rip, p);
     }
 
     if (error & pf_errors::USER) == 0 {
@@ -140,9 +133,14 @@ pub fn page_fault(p : &mut Process, error : u64) -> ! {
         abort("fault with kernel-space addr");
     }
 
-    let back = p.aspace().find_add_backing(fault_addr & !0xfff);
-    // FIXME should return e.g. Option<> so we can detect failures better than
-    // just aborting in find_add_backing.
+    let back = match p.aspace().find_add_backing(fault_addr & !0xfff) {
+        Some(back) => back,
+        None => abort("No mapping found!"),
+    };
+    // Anonymous backings are Phys too
+    use aspace::mapflag::*;
+    cpu().stats.fault(if (back.flags() & DMA) == Phys { stats::Fault::Phys } else { stats::Fault::Anon });
+    cpu().events.record(events::kind::PFAULT, p.id, fault_addr, error);
     p.aspace().add_pte(back.vaddr(), back.pte());
 
     unsafe { cpu().switch_to(p); }
@@ -154,10 +152,17 @@ pub fn handler_NM() {
 
 pub fn idle() -> ! {
     loop {
-        if log_idle {
-            write("idle\n");
+        if log::on(log::IDLE) {
+            kprintln!("idle");
         }
         cpu().process = None;
+        cpu().stats.leave();
+        if cmdline::get().stat_line {
+            mem::get().stat_line();
+        }
+        monitor::poll();
+        gdb::poll();
+        ktest::idle();
         unsafe { asm!("sti; hlt; cli" :::: "volatile"); }
     }
 }
@@ -170,6 +175,8 @@ pub struct PerCpu {
 
     // End of assembly-fixed fields.
     memory : mem::PerCpu,
+    stats : stats::PerCpu,
+    events : events::PerCpu,
     runqueue : DList<Process>,
 
     irq_process : Option<&'static mut Process>,
@@ -185,6 +192,8 @@ impl PerCpu {
             selfp : p,
             stack : stack,
             memory : mem,
+            stats : stats::PerCpu::new(),
+            events : events::PerCpu::new(),
             runqueue : DList::empty(),
             process : None,
             irq_process : None,
@@ -193,19 +202,38 @@ impl PerCpu {
         return p
     }
 
+    fn dump(&self) {
+        match self.process {
+            Some(ref p) => {
+                kprint!("current: ");
+                p.dump();
+                p.dump_regs();
+            },
+            None => kprintln!("current: idle"),
+        }
+        match self.irq_process {
+            Some(ref p) => kprintln!("irq process: {} {:p}", p.id, *p),
+            None => (),
+        }
+        kprintln!("irq_delayed: {:#x}", self.irq_delayed);
+        kprint!("runqueue:");
+        for p in self.runqueue.iter() {
+            kprint!(" {}", p.id);
+        }
+        kprintln!();
+    }
+
     unsafe fn start(&mut self) {
         setup_msrs(self.selfp as u64);
+        self.stats.start();
+        self.events.init(&mut self.memory, cmdline::get().event_pages);
     }
 
     fn queue(&mut self, p: &mut Process) {
-        if log_queue {
-            write("queue ");
-            con::writeMutPtr(p);
-            if p.is(process::Queued) {
-                write(" already queued");
-            }
-            con::newline();
+        if log::on(log::QUEUE) {
+            kprintln!("queue {:p}{}", p, if p.is(process::Queued) { " already queued" } else { "" });
         }
+        self.events.record(events::kind::QUEUE, p.id, p.is_queued() as u64, 0);
         if !p.is_queued() {
             p.set(process::Queued);
             self.runqueue.append(p);
@@ -227,21 +255,20 @@ impl PerCpu {
     }
 
     unsafe fn switch_to(&mut self, p: &mut Process) -> ! {
-        if log_switch {
-            write("switch_to ");
-            con::writeMutPtr(p);
-            write(" rip=");
-            con::writeHex(p.rip);
-            if p.is(process::FastRet) {
-                write(" fastret");
-            }
-            if p.is(process::Queued) {
-                write(" queued");
-            }
-            con::newline();
+        if p.is(process::Stopped) {
+            // Resuming it queues it again
+            self.run();
+        }
+        if log::on(log::SWITCH) {
+            kprintln!("switch_to {:p} rip={:#x}{}{}", p, p.rip,
+                if p.is(process::FastRet) { " fastret" } else { "" },
+                if p.is(process::Queued) { " queued" } else { "" });
         }
         p.set(process::Running);
         self.process = transmute(p as *mut Process);
+        self.stats.count.switches += 1;
+        self.stats.leave();
+        self.events.record(events::kind::SWITCH, p.id, p.rip, 0);
         // TODO Check fpu_process, see if we need to set/reset TS bit in cr0
         x86::set_cr3(p.cr3);
         extern "C" {
@@ -356,7 +383,9 @@ unsafe fn setup_msrs(gs : u64) {
     wrmsr(CSTAR, syscall_entry_compat as u64);
     // FIXME: We want to clear a lot more flags - Direction for instance.
     // FreeBSD sets PSL_NT|PSL_T|PSL_I|PSL_C|PSL_D
-    wrmsr(FMASK, rflags::IF | rflags::VM);
+    // TF too, so single-stepping a syscall instruction traps after sysret
+    // instead of in the kernel
+    wrmsr(FMASK, rflags::TF | rflags::IF | rflags::VM);
     wrmsr(EFER, rdmsr(EFER) | efer::SCE | efer::NXE);
     wrmsr(GSBASE, gs);
 }
@@ -364,91 +393,237 @@ unsafe fn setup_msrs(gs : u64) {
 #[lang="eh_personality"]
 fn dummy() {}
 
+// Copy the arguments onto the top of the initial stack (NUL-terminated), and
+// pass the address and length in rdi and rsi.
+fn push_args(p : &mut Process, args : &[u8]) {
+    let len = min(args.len(), max_args_len);
+    let vaddr = (p.regs().rsp - len as u64 - 1) & !0xf;
+    let dst = p.aspace().prefill_anon(vaddr);
+    unsafe {
+        let dst = dst.offset((vaddr & 0xfff) as isize);
+        copy_nonoverlapping(args.as_ptr(), dst, len);
+        *dst.offset(len as isize) = 0;
+    }
+    p.regs().rsp = vaddr;
+    p.regs().rdi = vaddr;
+    p.regs().rsi = len as u64;
+    // fastret doesn't restore rdi and rsi
+    p.unset(process::FastRet);
+}
+
+// Start a flat binary at 1MB. Modules in place in memory are mapped directly,
+// other images are copied.
 #[inline(never)]
-fn new_proc_simple(start : u32, end_unaligned : u32) -> *mut Process {
-    let end = ((end_unaligned + 0xfff) & !0xfff) as u64;
-    let start_page = (start & !0xfff) as u64;
+fn new_proc_simple(image : &Image, args : &[u8]) -> *mut Process {
     let aspace : *mut AddressSpace = AddressSpace::new();
     let ret : *mut Process = Process::new(aspace);
     unsafe {
         (*ret).regs().rsp = 0x100000;
-        (*ret).rip = 0x100000 + (start & 0xfff) as u64;
+        (*ret).rip = 0x100000;
     }
 
     unsafe {
         use aspace::mapflag::*;
         // Stack at 1MB - 4kB (not executable)
         (*aspace).mapcard_set(0x0ff000, 0, 0, Anon | R | W);
-        (*aspace).mapcard_set(0x100000, 0, start_page - 0x100000, Phys | R | X);
-        (*aspace).mapcard_set(0x100000 + (end - start_page), 0, 0, 0);
+        match image.paddr() {
+            Some(start) => {
+                let end = (start + image.len() + 0xfff) & !0xfff;
+                let start_page = start & !0xfff;
+                (*ret).rip += start & 0xfff;
+                (*ret).module = mem::Region::new(start, end);
+                (*aspace).mapcard_set(0x100000, 0, start_page - 0x100000, Phys | R | X);
+                (*aspace).mapcard_set(0x100000 + (end - start_page), 0, 0, 0);
+            },
+            None => {
+                let end = 0x100000 + ((image.len() + 0xfff) & !0xfff);
+                (*aspace).mapcard_set(0x100000, 0, 0, Anon | R | X);
+                (*aspace).mapcard_set(end, 0, 0, 0);
+                let mut offset = 0;
+                while offset < image.len() {
+                    let n = min(image.len() - offset, 0x1000);
+                    image.copy(offset, (*aspace).prefill_anon(0x100000 + offset), n);
+                    offset += n;
+                }
+            }
+        }
         /*match (*ret).aspace().mapcard_find(0x100000) {
             Some(c) => {
-                write("0x100000: ");
-                con::writePHex(c.vaddr());
-                write(" -> paddr ");
-                con::writePHex(c.paddr(0x100000));
-                con::newline();
+                kprintln!("0x100000: {:#018x} -> paddr {:#018x}", c.vaddr(), c.paddr(0x100000));
             },
             None => {
                 abort("mapcard we added is not there anymore");
             }
         }*/
+        push_args(&mut *ret, args);
     }
     return ret;
 }
 
-fn assoc_procs(p : &mut Process, i : u64, q : &mut Process, j : u64) {
-    if log_assoc_procs {
-        con::writeMutPtr(p);
-        con::putc(':');
-        con::writeUInt(i);
-        write(" <-> ");
-        con::writeUInt(j);
-        con::putc(':');
-        con::writeMutPtr(q);
-        con::newline();
-    }
-    p.assoc_handles(j, q, i);
+// Load an ELF executable. Returns null if the image is not a valid executable.
+#[inline(never)]
+fn new_proc_elf(image : &Image, args : &[u8]) -> *mut Process {
+    let aspace : *mut AddressSpace = AddressSpace::new();
+    let loaded = match elf::load(unsafe { &mut *aspace }, image) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            kerror!("ELF: {}", e);
+            return null_mut();
+        }
+    };
+    let ret : *mut Process = Process::new(aspace);
+    unsafe {
+        use aspace::mapflag::*;
+        // Same stack as for flat binaries
+        (*aspace).mapcard_set(0x0ff000, 0, 0, Anon | R | W);
+        (*aspace).mapcard_end(0x100000);
+        (*ret).regs().rsp = 0x100000;
+        (*ret).rip = loaded.entry;
+        if loaded.uses_image {
+            if let Some(start) = image.paddr() {
+                (*ret).module = mem::Region::new(start, start + image.len());
+            }
+        }
+        push_args(&mut *ret, args);
+    }
+    return ret;
 }
 
-fn init_modules(cpu : &mut PerCpu) {
-    let ref info = start32::MultiBootInfo();
-    if !info.has(mboot::Modules) {
-        return;
+// Decompress the image if needed, then start it as an ELF executable or a
+// flat binary. If owned is set, the image is a boot module of its own: the
+// process keeps it while it maps it directly, otherwise (or if the process
+// couldn't be started) it's freed here.
+pub fn new_proc(mut image : Image, args : &[u8], mut owned : bool) -> *mut Process {
+    let name = cmdline::words(args).next().unwrap_or(b"");
+    if inflate::is_compressed(&image, name) {
+        match inflate::inflate(&image) {
+            Ok(res) => {
+                kinfo!("Inflated to {} bytes", res.len());
+                if owned {
+                    release_image(&image);
+                    owned = false;
+                }
+                image = res;
+            },
+            Err(e) => {
+                kerror!("inflate: {}", e);
+                return null_mut();
+            }
+        }
     }
-    let mut head = DList::empty();
-    let mut count = 0;
-    for m in info.modules(start32::PhysAddr).iter() {
-        write("Module ");
-        con::writeHex(m.start);
-        write("..");
-        con::writeHex(m.end);
-        write(": ");
-        con::writeCStr(start32::PhysAddr(m.string as u64));
-        con::newline();
-
-        head.append(new_proc_simple(m.start, m.end));
-        count += 1;
+    let p = if elf::is_elf(&image) {
+        new_proc_elf(&image, args)
+    } else {
+        new_proc_simple(&image, args)
+    };
+    if !p.is_null() {
+        let p = unsafe { &mut *p };
+        if !owned {
+            // Part of the initrd, which stays as it is
+            p.module = mem::empty_region;
+        } else if p.module.is_empty() {
+            // Everything was copied, the module is not needed anymore.
+            release_image(&image);
+        }
+    } else if owned {
+        release_image(&image);
     }
-    con::writeUInt(count);
-    con::newline();
-    // Now all processes are in our list. We need to remove them before it
-    // gets possible to make them runnable.
-    let mut i = 0;
-    for p in head.iter() {
-        if i == 0 {
-            cpu.irq_process = Some(unsafe { &mut *(p as *mut Process) });
+    image.free();
+    p
+}
+
+fn release_image(image : &Image) {
+    if let Some(start) = image.paddr() {
+        mem::get().release(start, start + image.len());
+    }
+}
+
+// Give the initrd to the init process: the module with that name, or else the
+// program with that name in the archive. Returns the init process, or null if
+// there is none or the archive can't be mapped into it.
+fn init_initrd(archive : &initrd::Archive, topology : &mut Topology, head : &mut DList<Process>) -> *mut Process {
+    let name = cmdline::get().init;
+    let p = match topology.find(name) {
+        Some(i) => topology.process(i) as *mut Process,
+        None => match archive.find(name) {
+            Some(f) => {
+                let p = new_proc(Image::Phys(archive.paddr + f.offset, f.size), name, false);
+                if !p.is_null() {
+                    topology.add(name, name, p);
+                    head.append(p);
+                }
+                p
+            },
+            None => null_mut(),
+        },
+    };
+    if p.is_null() {
+        kwarn!("initrd: no init process \"{}\"", Bytes(name));
+        return p;
+    }
+    let init = unsafe { &mut *p };
+    if !archive.map_into(init) {
+        kerror!("initrd: \"{}\" uses {:#x}, where the archive goes", Bytes(name), initrd::vaddr);
+        return null_mut();
+    }
+    init.set(process::Privileged);
+    init.set(process::Inspect);
+    p
+}
+
+fn init_modules(cpu : &mut PerCpu) {
+    let mut head = DList::empty();
+    let mut topology = Topology::new();
+    for m in boot::get().modules().iter() {
+        kinfo!("Module {:#x}..{:#x}: {}", m.start, m.end, Bytes(m.cmdline()));
+
+        if initrd::is_archive(m.name(), m.start, m.end - m.start) {
+            initrd::set(initrd::Archive { paddr : m.start, size : m.end - m.start });
+            continue;
         }
-        i += 1;
-        // start iterating at j = i + 1, and q = p.next...
-        let mut j = 0;
-        for q in head.iter() {
-            j += 1;
-            if i < j {
-                assoc_procs(p, i, q, j);
-            }
+        let p = new_proc(Image::module(m.start, m.end), m.cmdline(), true);
+        if p.is_null() {
+            kerror!("Module not started");
+            continue;
         }
+        topology.add(m.name(), m.cmdline(), p);
+        head.append(p);
+    }
+    let init = match initrd::get() {
+        Some(ref a) => init_initrd(a, &mut topology, &mut head),
+        None => null_mut(),
+    };
+    kinfo!("{} processes", topology.count());
+    if topology.count() == 0 {
+        return;
     }
+
+    let irq_name = cmdline::get().irq_proc;
+    let irq_index = match topology.irq_node() {
+        Some(i) => i,
+        None if irq_name.len() > 0 => match topology.find(irq_name) {
+            Some(i) => i,
+            None => {
+                kwarn!("irqproc: no module named {}", Bytes(irq_name));
+                0
+            }
+        },
+        None => 0,
+    };
+    // The IRQ process and the init process are privileged.
+    let p = topology.process(irq_index);
+    p.set(process::Privileged);
+    p.set(process::Inspect);
+    cpu.irq_process = Some(p);
+
+    // The init process, or else the first module, gets the boot info page.
+    let first = if init.is_null() { topology.process(0) } else { unsafe { &mut *init } };
+    bootinfo::map(first.aspace(), bootinfo::vaddr);
+
+    topology.connect();
+
+    // Now all processes are in our list. We need to remove them before it
+    // gets possible to make them runnable.
     while match head.pop() {
         Some(p) => { cpu.queue(unsafe { &mut *p }); true },
         None => false
@@ -460,38 +635,76 @@ pub fn dump_runqueue(queue: &DList<Process>) {
     for _ in queue.iter() {
         count += 1;
     }
-    con::write("runqueue: ");
-    con::writeUInt(count);
-    con::newline();
+    kprintln!("runqueue: {}", count);
     for p in queue.iter() {
         p.dump();
     }
 }
 
+// The CPU's state and all processes, for crash dumps.
+pub fn dump_processes() {
+    cpu().dump();
+    for p in process::all() {
+        p.dump();
+    }
+}
+
+// The boot loader knows the text mode better than the BIOS data area, if it
+// says. The memory status line takes the top line of the screen.
+fn init_text_console(info : &boot::Info) {
+    let c = con::get();
+    match info.fb {
+        Some(ref fb) if fb.fbtype == mboot::FBType::Text as u8 => {
+            c.set_geometry(fb.width as usize, fb.height as usize);
+        },
+        _ => (),
+    }
+    if cmdline::get().mem_stat {
+        c.reserve_top(1);
+    }
+}
+
 #[no_mangle]
 pub unsafe fn start64() -> ! {
-    con::init(MutPhysAddr(0xb80a0));
+    let (cols, rows) = con::bios_geometry();
+    con::init(MutPhysAddr(0xb8000), cols, rows);
+    serial::init(serial::COM1, 115200);
     con::clear();
-    write("Hello World!\n");
-    //writeMBInfo(start32::MultiBootInfo());
+    kprintln!("Hello World!");
 
     x86::lgdt(start32::Gdtr());
     x86::ltr(x86::seg::tss64);
 
     idt::init();
 
-    mem::global.init(start32::MultiBootInfo(), start32::MemoryStart(), start32::MemoryEnd());
-    write("Memory initialized. ");
+    boot::init(start32::BootMagic(), start32::BootInfoAddr());
+    cmdline::parse(boot::get().cmdline);
+    init_text_console(boot::get());
+    boot::get().print();
+    if !serial::init(cmdline::get().serial_port, cmdline::get().serial_baud) {
+        kwarn!("serial: no UART at {:#x}", cmdline::get().serial_port);
+    }
+
+    let mut mem_end = start32::MemoryEnd();
+    if cmdline::get().mem_limit != 0 && cmdline::get().mem_limit < mem_end {
+        mem_end = cmdline::get().mem_limit;
+    }
+    mem::global.init(boot::get(), start32::MemoryStart(), mem_end);
+    kprint!("Memory initialized. ");
     mem::global.stat();
 
     let pcpu = PerCpu::new();
     let ref mut cpu = *pcpu;
     cpu.start();
-    if mem_test {
+    fbcon::init(boot::get());
+    klog::init();
+    gdb::init();
+    if cmdline::get().mem_test {
         cpu.memory.test();
         mem::global.stat();
     }
 
+    ktest::run();
     init_modules(cpu);
     //dump_runqueue(&cpu.runqueue);
 
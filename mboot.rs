@@ -2,6 +2,9 @@ use core::slice;
 pub use self::MemoryTypes::*;
 pub use self::InfoFlags::*;
 
+pub const Magic : u32 = 0x2badb002;
+
+#[repr(C)]
 pub struct VBE {
     control_info: u32,
     mode_info   : u32,
@@ -11,16 +14,33 @@ pub struct VBE {
     iface_len   : u16,
 }
 
+#[repr(C)]
 pub struct FB {
-    addr        : u64,
-    pitch       : u32,
-    width       : u32,
-    height      : u32,
-    bpp         : u8,
-    fbtype      : u8,
+    pub addr    : u64,
+    pub pitch   : u32,
+    pub width   : u32,
+    pub height  : u32,
+    pub bpp     : u8,
+    pub fbtype  : u8,
+    // FBPalette for Indexed, FBPixelFormat for RGB
     colors      : [u8; 6],
 }
 
+impl FB {
+    // Size of the framebuffer in bytes
+    pub fn size(&self) -> u64 {
+        self.pitch as u64 * self.height as u64
+    }
+
+    pub fn palette(&self) -> &FBPalette {
+        unsafe { &*(&self.colors as *const [u8; 6] as *const FBPalette) }
+    }
+
+    pub fn pixel_format(&self) -> FBPixelFormat {
+        unsafe { *(&self.colors as *const [u8; 6] as *const FBPixelFormat) }
+    }
+}
+
 #[allow(dead_code)]
 pub enum FBType {
     Indexed = 0,
@@ -28,20 +48,22 @@ pub enum FBType {
     Text = 2,
 }
 
-#[allow(dead_code)]
+#[repr(C, packed)]
 pub struct FBPalette {
-    addr        : u32,
-    count       : u16,
+    pub addr    : u32,
+    pub count   : u16,
 }
 
-#[allow(dead_code)]
+// The masks are the number of bits in each field.
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct FBPixelFormat {
-    red_shift   : u8,
-    red_mask    : u8,
-    green_shift : u8,
-    green_mask  : u8,
-    blue_shift  : u8,
-    blue_mask   : u8,
+    pub red_shift   : u8,
+    pub red_mask    : u8,
+    pub green_shift : u8,
+    pub green_mask  : u8,
+    pub blue_shift  : u8,
+    pub blue_mask   : u8,
 }
 
 pub enum InfoFlags {
@@ -59,9 +81,11 @@ pub enum InfoFlags {
     ConfigTable = 256,
     LoaderName = 512,
     APMTable = 1024,
-    VBEInfo = 2048
+    VBEInfo = 2048,
+    Framebuffer = 4096,
 }
 
+#[repr(C)]
 pub struct Info {
     pub flags   : u32,
 // if has(MemorySize)
@@ -93,7 +117,7 @@ pub struct Info {
     apm_table   : u32,
 
     vbe         : VBE,
-    fb          : FB,
+    pub fb      : FB,
 }
 
 impl Info {
@@ -106,6 +130,7 @@ impl Info {
     }
 }
 
+#[repr(C)]
 #[allow(dead_code)] // Spurious dead-code warning?
 pub struct Module {
     pub start   : u32,
@@ -0,0 +1,142 @@
+// Multiboot 2 boot information: a header followed by a list of 8-byte aligned
+// tags, terminated by an End tag.
+use core::mem::size_of;
+
+pub use self::TagType::*;
+
+pub const Magic : u32 = 0x36d76289;
+
+#[repr(C)]
+pub struct InfoHeader {
+    pub total_size  : u32,
+    reserved        : u32,
+}
+
+pub enum TagType {
+    End = 0,
+    CommandLine = 1,
+    LoaderName = 2,
+    Module = 3,
+    BasicMemInfo = 4,
+    BootDevice = 5,
+    MemoryMap = 6,
+    VBEInfo = 7,
+    Framebuffer = 8,
+    ELFSections = 9,
+    APMTable = 10,
+    EFI32 = 11,
+    EFI64 = 12,
+    SMBIOS = 13,
+    ACPIOld = 14,
+    ACPINew = 15,
+    Network = 16,
+    EFIMemoryMap = 17,
+}
+
+#[repr(C)]
+pub struct Tag {
+    pub tag_type    : u32,
+    // Size including this header, but not the padding up to the next tag
+    pub size        : u32,
+}
+
+impl Tag {
+    pub fn is(&self, t : TagType) -> bool {
+        self.tag_type == t as u32
+    }
+
+    // Start of the tag-specific data following the header
+    pub fn data_addr(&self) -> u64 {
+        self as *const Tag as u64 + size_of::<Tag>() as u64
+    }
+
+    pub fn data_size(&self) -> u64 {
+        self.size as u64 - size_of::<Tag>() as u64
+    }
+
+    pub unsafe fn data<'a, T>(&self) -> &'a T {
+        &*(self.data_addr() as *const T)
+    }
+}
+
+#[repr(C)]
+pub struct BasicMemInfo {
+    pub mem_lower   : u32,
+    pub mem_upper   : u32,
+}
+
+#[repr(C)]
+pub struct Module {
+    pub start   : u32,
+    pub end     : u32,
+    // Followed by the NUL-terminated command line.
+}
+
+#[repr(C)]
+pub struct MemoryMap {
+    pub entry_size      : u32,
+    pub entry_version   : u32,
+    // Followed by entries of entry_size bytes, see MemoryMapEntry.
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemoryMapEntry {
+    pub start       : u64,
+    pub length      : u64,
+    // Same values as the multiboot 1 memory types
+    pub item_type   : u32,
+    reserved        : u32,
+}
+
+#[repr(C)]
+pub struct Framebuffer {
+    pub addr    : u64,
+    pub pitch   : u32,
+    pub width   : u32,
+    pub height  : u32,
+    pub bpp     : u8,
+    pub fbtype  : u8,
+    reserved    : u16,
+    // Followed by color info, depending on fbtype:
+    // Indexed: u16 count, followed by count 3-byte RGB palette entries
+    // RGB: a mboot::FBPixelFormat
+}
+
+#[repr(C)]
+pub struct EFIMemoryMap {
+    pub desc_size   : u32,
+    pub desc_version: u32,
+    // Followed by the EFI memory descriptors
+}
+
+pub struct Tags {
+    addr : u64,
+    end : u64,
+}
+
+// Iterate the tags of the info structure at vaddr (kernel-virtual).
+pub fn tags(vaddr : u64) -> Tags {
+    let header = unsafe { &*(vaddr as *const InfoHeader) };
+    Tags {
+        addr : vaddr + size_of::<InfoHeader>() as u64,
+        end : vaddr + header.total_size as u64,
+    }
+}
+
+impl Iterator for Tags {
+    type Item = &'static Tag;
+
+    fn next(&mut self) -> Option<&'static Tag> {
+        if self.addr + size_of::<Tag>() as u64 > self.end {
+            return None;
+        }
+        let tag = unsafe { &*(self.addr as *const Tag) };
+        if tag.is(End) || (tag.size as usize) < size_of::<Tag>() {
+            self.addr = self.end;
+            return None;
+        }
+        self.addr += (tag.size as u64 + 7) & !7;
+        Some(tag)
+    }
+}
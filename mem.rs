@@ -1,24 +1,24 @@
+use core::cmp::{min, max};
+use core::fmt::Write;
 use core::intrinsics::{write_bytes, copy_nonoverlapping};
 use core::ptr;
 
+use boot;
+use cmdline;
 use con;
 use con::Console;
 use con::Writer;
-use con::write;
-use con::writeUInt;
+use log;
 use mboot;
-use mboot::MemoryMapItem;
 use start32::PhysAddr;
 use start32::MutPhysAddr;
+use start32::kernel_base;
+use stats;
 use util::abort;
+use util::strlen;
 
 use mem::framestack::*;
 
-static log_alloc : bool = false;
-static log_memory_map : bool = false;
-static log_memtest : bool = false;
-static mem_stats : bool = true;
-
 pub mod framestack {
     pub struct FreeFrame {
         next : FreeFrameS
@@ -60,6 +60,40 @@ pub mod framestack {
 
 }
 
+// A page-aligned range of physical memory, start inclusive and end exclusive.
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub start : u64,
+    pub end : u64,
+}
+
+pub const empty_region : Region = Region { start : 0, end : 0 };
+
+impl Region {
+    // Round outwards to whole pages.
+    pub fn new(start : u64, end : u64) -> Region {
+        Region { start : start & !0xfff, end : (end + 0xfff) & !0xfff }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    pub fn contains(&self, addr : u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn inside(&self, other : &Region) -> bool {
+        other.start <= self.start && self.end <= other.end
+    }
+}
+
+// The boot info tables and command line, and each module with its command line
+const max_reserved : usize = boot::max_tables + 1 + 2 * boot::max_modules;
+
+// Written at the start of each MB when probing for memory without a map
+const probe_pattern : u64 = 0x5a5a_a5a5_0ff0_f00f;
+
 pub struct Global {
     // Frames that are uninitialized (except for the first word)
     garbage : FreeFrameS,
@@ -67,38 +101,46 @@ pub struct Global {
     free : FreeFrameS,
     num_used : usize,
     num_total : usize,
+    // Physical memory that must never end up on the free lists: boot modules,
+    // their command lines and the multiboot structures.
+    reserved : [Region; max_reserved],
+    num_reserved : usize,
+    // The part of physical memory that the allocator may use at all
+    min_addr : u64,
+    max_addr : u64,
 }
 
-pub const empty_global : Global = Global { garbage : none, free : none, num_used : 0, num_total : 0 };
+pub const empty_global : Global = Global {
+    garbage : none, free : none, num_used : 0, num_total : 0,
+    reserved : [empty_region; max_reserved], num_reserved : 0,
+    min_addr : 0, max_addr : 0 };
 pub static mut global : Global = empty_global;
 
 pub struct PerCpu {
     free : FreeFrameS
 }
 
-struct MemoryMap {
-    addr : *const u8,
-    end : *const u8
-}
-
-impl MemoryMap {
-    fn new(addr : *const u8, length : usize) -> MemoryMap {
-        return MemoryMap { addr : addr, end : unsafe { addr.offset(length as isize) } }
-    }
-}
-
-impl Iterator for MemoryMap {
-    type Item = MemoryMapItem;
-
-    fn next(&mut self) -> Option<MemoryMapItem> {
-        if self.addr < self.end { unsafe {
-            let item = *(self.addr as *const MemoryMapItem);
-            self.addr = self.addr.offset(4 + item.item_size as isize);
-            Some(item)
-        } } else {
-            None
+// Without any help from the boot loader, look for RAM above the kernel one MB
+// at a time, until some MB doesn't keep a test pattern. Conservatively stops
+// at the ISA memory hole at 15MB. Returns the end of memory found.
+fn probe(min_addr : u64, max_addr : u64) -> u64 {
+    let limit = min(max_addr, 15 << 20);
+    // We're running in the MB that min_addr is in, so that much must exist.
+    let mut end = (min_addr + 0xfffff) & !0xfffff;
+    while end < limit {
+        let p : *mut u64 = MutPhysAddr(end);
+        unsafe {
+            let old = ptr::read_volatile(p);
+            ptr::write_volatile(p, probe_pattern);
+            let found = ptr::read_volatile(p) == probe_pattern;
+            ptr::write_volatile(p, old);
+            if !found {
+                break;
+            }
         }
+        end += 1 << 20;
     }
+    min(end, limit)
 }
 
 fn clear<T>(page : *mut T) {
@@ -106,49 +148,141 @@ fn clear<T>(page : *mut T) {
 }
 
 impl Global {
-    pub fn init(&mut self, info : &mboot::Info, min_addr : u64, max_addr : u64) {
-        if !info.has(mboot::MemoryMap) {
-            return;
+    pub fn init(&mut self, info : &boot::Info, min_addr : u64, max_addr : u64) {
+        self.min_addr = min_addr;
+        self.max_addr = max_addr;
+        self.reserve_boot_info(info);
+
+        let mut count = 0;
+        match info.mmap {
+            Some(mmap) => {
+                kinfo!("Memory: using memory map");
+                for item in mmap.iter() {
+                    if log::on(log::MEMORY_MAP) {
+                        kprintln!("start={:#018x} length={:#018x} type={}", item.start, item.length, item.item_type);
+                    }
+                    if item.item_type != mboot::MemoryTypeMemory as u32 {
+                        continue;
+                    }
+                    count += self.add_range(item.start, item.start + item.length, min_addr, max_addr);
+                }
+            },
+            None if info.mem_upper != 0 => {
+                // mem_upper is the memory from 1MB up to the first hole.
+                kinfo!("Memory: no memory map, using {}kB lower and {}kB upper memory",
+                    info.mem_lower, info.mem_upper);
+                count += self.add_range(0, info.mem_lower as u64 * 1024, min_addr, max_addr);
+                let upper_end = 0x100000 + info.mem_upper as u64 * 1024;
+                count += self.add_range(0x100000, upper_end, min_addr, max_addr);
+            },
+            None => {
+                kwarn!("Memory: no memory information from boot loader, probing");
+                let end = probe(min_addr, max_addr);
+                count += self.add_range(min_addr, end, min_addr, max_addr);
+            },
         }
+        self.num_used = 0;
+        self.num_total = count;
+
+        kinfo!("Memory: {} frames added, {} regions reserved", count, self.num_reserved);
+        if count == 0 {
+            abort("No usable memory found");
+        }
+    }
 
-        let mmap = MemoryMap::new(PhysAddr(info.mmap_addr as u64), info.mmap_length as usize);
+    // Add the whole pages between start and end that are inside min..max and
+    // not reserved. Returns the number of frames added.
+    fn add_range(&mut self, start : u64, end : u64, min_addr : u64, max_addr : u64) -> usize {
+        let mut addr = (max(start, min_addr) + 0xfff) & !0xfff;
+        let end = min(end, max_addr) & !0xfff;
         let mut count = 0;
-        for item in mmap {
-            if log_memory_map {
-                write("start=");
-                con::writePHex(item.start);
-                write(" length=");
-                con::writePHex(item.length);
-                write(" type=");
-                con::writeUInt(item.item_type);
-                con::newline();
+        while addr < end {
+            if !self.is_reserved(addr) {
+                self.num_used = 1;
+                self.free_frame(MutPhysAddr(addr));
+                count += 1;
+            }
+            addr += 4096;
+        }
+        count
+    }
+
+    pub fn reserve(&mut self, start : u64, end : u64) {
+        let r = Region::new(start, end);
+        if r.is_empty() {
+            return;
+        }
+        if log::on(log::RESERVE) {
+            kprintln!("reserve {:#018x}..{:#018x}", r.start, r.end);
+        }
+        if self.num_reserved == max_reserved {
+            abort("Too many reserved memory regions");
+        }
+        self.reserved[self.num_reserved] = r;
+        self.num_reserved += 1;
+    }
+
+    fn reserve_cstr(&mut self, paddr : u64) {
+        if paddr != 0 {
+            let len = strlen(PhysAddr(paddr)) as u64;
+            self.reserve(paddr, paddr + len + 1);
+        }
+    }
+
+    // Everything the boot info refers to, and the boot loader's structures.
+    // The framebuffer isn't RAM, so it's left out.
+    fn reserve_boot_info(&mut self, info : &boot::Info) {
+        for t in info.tables().iter() {
+            self.reserve(t.start, t.end);
+        }
+        self.reserve_cstr(info.cmdline);
+        for m in info.modules().iter() {
+            self.reserve(m.start, m.end);
+            self.reserve_cstr(m.string);
+        }
+    }
+
+    pub fn is_reserved(&self, paddr : u64) -> bool {
+        for r in self.reserved[..self.num_reserved].iter() {
+            if r.contains(paddr) {
+                return true;
             }
-            if item.item_type != mboot::MemoryTypeMemory as u32 {
-                continue;
+        }
+        false
+    }
+
+    // Drop the reservations inside start..end and give the pages to the
+    // allocator. Pages shared with some other reserved region stay reserved,
+    // and pages outside the memory given to init aren't added.
+    pub fn release(&mut self, start : u64, end : u64) {
+        let r = Region::new(start, end);
+        let mut i = 0;
+        while i < self.num_reserved {
+            if self.reserved[i].inside(&r) {
+                self.num_reserved -= 1;
+                self.reserved[i] = self.reserved[self.num_reserved];
+            } else {
+                i += 1;
             }
-            let mut p = item.start;
-            while p <= item.start + item.length {
-                let addr = p as u64;
-                if min_addr <= addr && addr < max_addr {
-                    self.num_used = 1;
-                    self.free_frame(MutPhysAddr(addr));
-                    count += 1;
-                }
-                p += 4096;
+        }
+        let mut addr = max(r.start, (self.min_addr + 0xfff) & !0xfff);
+        let end = min(r.end, self.max_addr & !0xfff);
+        while addr < end {
+            if !self.is_reserved(addr) {
+                self.num_total += 1;
+                self.num_used += 1;
+                self.free_frame(MutPhysAddr(addr));
             }
+            addr += 4096;
         }
-        self.num_used = 0;
-        self.num_total = count;
     }
 
     pub fn free_frame(&mut self, vpaddr : *mut u8) {
         self.num_used -= 1;
-        if log_alloc {
-            write("free_frame: ");
-            con::writeMutPtr(vpaddr);
-            con::newline();
+        if log::on(log::ALLOC) {
+            kprintln!("free_frame: {:p}", vpaddr);
         }
-        if mem_stats {
+        if cmdline::get().mem_stat {
             self.stat_line();
         }
         push_frame(&mut self.garbage, vpaddr);
@@ -169,12 +303,10 @@ impl Global {
                 None => { None }
             }
         };
-        if log_alloc {
-            write("alloc_frame: ");
-            con::writeMutPtr(store(res));
-            con::newline();
+        if log::on(log::ALLOC) {
+            kprintln!("alloc_frame: {:p}", store(res));
         }
-        if mem_stats {
+        if cmdline::get().mem_stat {
             self.stat_line();
         }
         res
@@ -189,26 +321,25 @@ impl Global {
     }
 
     #[inline(never)]
-    fn stat_line(&self) {
-        use start32::kernel_base;
-        let mut con = Console::new((kernel_base + 0xb8000) as *mut u16);
+    pub fn stat_line(&self) {
+        // Two lines high so the newline at the end doesn't scroll
+        let mut con = Console::new((kernel_base + 0xb8000) as *mut u16, con::get().width(), 2);
         con.debug = false;
         con.color = 0x2f00;
         con.write("Memory: ");
-        self.stat_(&mut con);
+        let _ = write!(con, "Free: {}KiB, Used: {}KiB", self.free_pages() * 4, self.used_pages() * 4);
+        stats::stat_line(&mut con);
+        con.write("\n");
     }
 
     #[inline(never)]
     pub fn stat(&self) {
         self.stat_(con::get());
+        con::get().sync_cursor();
     }
 
     fn stat_(&self, con: &mut Console) {
-        con.write("Free: ");
-        con.writeUInt(self.free_pages() * 4);
-        con.write("KiB, Used: ");
-        con.writeUInt(self.used_pages() * 4);
-        con.write("KiB\n");
+        let _ = write!(con, "Free: {}KiB, Used: {}KiB\n", self.free_pages() * 4, self.used_pages() * 4);
     }
 }
 
@@ -265,12 +396,8 @@ impl PerCpu {
         let mut count = 0;
         loop {
             let p = self.alloc_frame_();
-            if log_memtest {
-                write("Allocation #");
-                writeUInt(count);
-                write(": ");
-                con::writeMutPtr(p);
-                con::newline();
+            if log::on(log::MEMTEST) {
+                kprintln!("Allocation #{}: {:p}", count, p);
                 get().stat();
             }
             if p.is_null() {
@@ -279,19 +406,13 @@ impl PerCpu {
             push_frame(&mut head, p);
             count += 1;
         }
-        if log_memtest {
-            write("Allocated everything: ");
-            writeUInt(count);
-            write(" pages\n");
+        if log::on(log::MEMTEST) {
+            kprintln!("Allocated everything: {} pages", count);
             get().stat();
         }
         loop {
-            if log_memtest {
-                write("Allocation #");
-                writeUInt(count);
-                write(": ");
-                con::writeMutPtr(head);
-                con::newline();
+            if log::on(log::MEMTEST) {
+                kprintln!("Allocation #{}: {:p}", count, head);
                 get().stat();
             }
             match pop_frame(&mut head) {
@@ -9,4 +9,12 @@ menuentry "test" {
     boot
 }
 
+menuentry "test (multiboot 2)" {
+    multiboot2 (cd)/kernel
+    module2 (cd)/test.mod asdf
+    module2 (cd)/test.mod jkl
+    module2 (cd)/test.mod 123
+    boot
+}
+
 EOF
@@ -0,0 +1,29 @@
+#!/bin/bash
+# Generate the kernel symbol table used for backtraces (see backtrace.rs) as
+# yasm source, from the functions in the ELF file given as argument. With no
+# argument, generate an empty table for the first link.
+
+if [ -n "$1" ]; then
+    nm -n -C --defined-only "$1" | grep -E '^[0-9a-f]+ [tT] ' |
+        sed -E 's/::h[0-9a-f]{16}$//'
+fi | awk '
+BEGIN { n = 0 }
+{
+    addr[n] = $1
+    name = $0
+    sub(/^[^ ]+ [^ ]+ /, "", name)
+    names[n++] = name
+}
+END {
+    print "section .ksyms progbits alloc noexec nowrite align=8"
+    print "global ksyms"
+    print "ksyms:"
+    print "\tdq " n
+    for (i = 0; i < n; i++) print "\tdq 0x" addr[i]
+    offset = 0
+    for (i = 0; i < n; i++) {
+        print "\tdd " offset
+        offset += length(names[i]) + 1
+    }
+    for (i = 0; i < n; i++) print "\tdb \"" names[i] "\", 0"
+}'
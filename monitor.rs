@@ -0,0 +1,134 @@
+// Kernel monitor: a few debugging commands typed on the serial console.
+use boot;
+use cmdline::parse_uint;
+use cmdline::words;
+use con::Bytes;
+use cpu;
+use dump_runqueue;
+use gdb;
+use log;
+use mem;
+use process;
+use serial;
+
+fn help() {
+    kprintln!("Commands:");
+    kprintln!("  help          this text");
+    kprintln!("  boot          boot loader information");
+    kprintln!("  mem           memory usage");
+    kprintln!("  ps            current process and run queue");
+    kprintln!("  log           show the log level and enabled log categories");
+    kprintln!("  log +a -b c   enable a, disable b, or set exactly c");
+    kprintln!("  gdb           stop in the GDB stub");
+    kprintln!("  trace n [off] trace syscalls of process n, or stop tracing");
+    kprintln!("  stats         event counters");
+    kprintln!("  events        dump the event trace buffer (serial and debugcon)");
+}
+
+fn ps() {
+    let c = cpu();
+    match c.process {
+        Some(ref p) => {
+            kprint!("current: ");
+            p.dump();
+        },
+        None => kprintln!("current: idle"),
+    }
+    match c.irq_process {
+        Some(ref p) => {
+            kprint!("irq process: ");
+            p.dump();
+        },
+        None => (),
+    }
+    dump_runqueue(&c.runqueue);
+}
+
+fn show_log() {
+    kprint!("log mask {:#x}:", log::get());
+    for &(name, m) in log::names.iter() {
+        if m != log::ALL && log::on(m) {
+            kprint!(" {}", name);
+        }
+    }
+    kprintln!();
+    for &(name, l) in log::level_names.iter() {
+        if l == log::get_level() {
+            kprintln!("log level {}", name);
+        }
+    }
+}
+
+fn set_log<'a, I : Iterator<Item = &'a [u8]>>(args : I) {
+    let mut mask = log::get();
+    let mut exact = 0;
+    let mut any_exact = false;
+    for arg in args {
+        let (op, name) = match arg[0] {
+            b'+' | b'-' => (arg[0], &arg[1..]),
+            _ => (b'=', arg),
+        };
+        let m = log::by_name(name);
+        if m == 0 {
+            kprintln!("unknown log category \"{}\"", Bytes(name));
+            return;
+        }
+        match op {
+            b'+' => mask |= m,
+            b'-' => mask &= !m,
+            _ => {
+                exact |= m;
+                any_exact = true;
+            },
+        }
+    }
+    log::set(if any_exact { exact } else { mask });
+    show_log();
+}
+
+fn trace<'a, I : Iterator<Item = &'a [u8]>>(mut args : I) {
+    let p = match args.next().and_then(parse_uint).and_then(|id| process::by_id(id as u32)) {
+        Some(p) => p,
+        None => {
+            kprintln!("no such process");
+            return;
+        },
+    };
+    match args.next() {
+        Some(b"off") => p.unset(process::Traced),
+        _ => p.set(process::Traced),
+    }
+}
+
+fn command(line : &[u8]) {
+    let mut args = words(line);
+    match args.next() {
+        None => (),
+        Some(b"help") => help(),
+        Some(b"boot") => boot::get().print(),
+        Some(b"mem") => mem::get().stat(),
+        Some(b"ps") => ps(),
+        Some(b"log") => {
+            let mut rest = args.peekable();
+            if rest.peek().is_none() {
+                show_log();
+            } else {
+                set_log(rest);
+            }
+        },
+        Some(b"trace") => trace(args),
+        Some(b"stats") => cpu().stats.print(),
+        Some(b"events") => cpu().events.dump(),
+        Some(b"gdb") => if !gdb::enter() {
+            kprintln!("no gdb stub, see the gdb= option");
+        },
+        Some(cmd) => {
+            kprintln!("unknown command \"{}\", try help", Bytes(cmd));
+        },
+    }
+}
+
+// Run the commands received since last time.
+pub fn poll() {
+    serial::poll(command);
+}
@@ -4,12 +4,11 @@ use alloc;
 use free;
 
 use aspace::AddressSpace;
-use con;
-use con::write;
 use dlist::DList;
 use dlist::DListNode;
 use dlist::DListItem;
 use dict::*;
+use mem;
 
 pub use self::FlagBit::*;
 
@@ -45,9 +44,28 @@ pub enum FlagBit {
 // Process has had a page fault that requires a response from a backer, or has
 // requested a page paged in.
 // proc.fault_addr is the address that faulted/was requested.
-    PFault = 5
+    PFault = 5,
+// Allowed to use privileged syscalls, e.g. LOGCTL.
+    Privileged = 6,
+// Syscalls and IPC of the process are traced in the kernel log (see the TRACE
+// syscall).
+    Traced = 7,
+// Capability to use the INFO syscall, and to give it to other processes.
+    Inspect = 8,
+// Suspended by its debugger (see debug.rs). IPC to and from it still
+// completes, but it doesn't run until resumed.
+    Stopped = 9,
+// Stopped at a trap that hasn't been reported to the debugger yet, because
+// the debugger wasn't receiving. Waits on the debugger like a sender.
+    DebugStop = 10,
 }
 
+// Names of the flags, by bit number, for dumps.
+pub const flag_names : [&'static str; 11] = [
+    "queued", "fastret", "recv", "send", "running", "pfault", "privileged",
+    "traced", "inspect", "stopped", "debugstop",
+];
+
 impl FlagBit {
     #[inline]
     pub fn mask(self) -> Flags {
@@ -68,6 +86,8 @@ pub struct Handle {
     // in other-proc yet.
     pub other : Option<*mut Handle>,
     pulses : u64,
+    // Capability to debug the process through this handle, see debug.rs
+    pub debug : bool,
 }
 
 impl DictItem for Handle {
@@ -124,6 +144,10 @@ impl Handle {
         self.pulses = 0;
         return res;
     }
+
+    pub fn pulses(&self) -> u64 {
+        self.pulses
+    }
 }
 
 pub struct PendingPulse {
@@ -170,7 +194,7 @@ pub struct Regs {
 impl Regs {
 }
 
-type Flags = u8;
+pub type Flags = u16;
 
 pub struct Process {
     // Regs must be first since it's used by assembly code.
@@ -206,9 +230,57 @@ pub struct Process {
     // The lower bits are access flags for the fault/request.
     pub fault_addr: u64,
 
+    // The process debugging this one (if any) and its handle for us.
+    debugger : *mut Process, // Option
+    pub debugger_handle : u64,
+    // When Stopped, the vector of the trap that stopped it (#DB or #BP), or 0
+    // if the debugger suspended it.
+    pub trap : u8,
+
+    // Physical memory of the boot module this process was started from, if
+    // it's mapped directly into the process. It stays reserved in the frame
+    // allocator for as long as the process exists (see new_proc for modules
+    // that aren't mapped), and processes don't exit yet.
+    pub module : mem::Region,
+
+    // Number in order of creation, starting at 1
+    pub id : u32,
+    // Next process in the list of all processes
+    next_process : *mut Process,
+
     //fxsave : FXSaveRegs,
 }
 
+// All processes, in order of creation.
+static mut first_process : *mut Process = 0 as *mut Process;
+static mut last_process : *mut Process = 0 as *mut Process;
+static mut num_processes : u32 = 0;
+
+pub struct AllProcesses {
+    next : *mut Process,
+}
+
+impl Iterator for AllProcesses {
+    type Item = &'static mut Process;
+
+    fn next(&mut self) -> Option<&'static mut Process> {
+        if self.next.is_null() {
+            return None;
+        }
+        let p = unsafe { &mut *self.next };
+        self.next = p.next_process;
+        Some(p)
+    }
+}
+
+pub fn all() -> AllProcesses {
+    AllProcesses { next : unsafe { first_process } }
+}
+
+pub fn by_id(id : u32) -> Option<&'static mut Process> {
+    all().find(|p| p.id == id)
+}
+
 impl DListItem for Process {
     fn node<'a>(&'a mut self) -> &'a mut DListNode<Process> {
         return &mut self.node;
@@ -228,9 +300,23 @@ impl Process {
     pub fn new(aspace : *mut AddressSpace) -> *mut Process {
         let res = alloc::<Process>();
         res.init(aspace);
+        unsafe {
+            num_processes += 1;
+            res.id = num_processes;
+            if last_process.is_null() {
+                first_process = res;
+            } else {
+                (*last_process).next_process = res;
+            }
+            last_process = res;
+        }
         res as *mut Process
     }
 
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
     pub fn regs<'a>(&'a mut self) -> &'a mut Regs {
         &mut self.regs
     }
@@ -266,6 +352,24 @@ impl Process {
     }
 
     #[inline(never)]
+    pub fn handles<'a>(&'a self) -> DictIter<'a, Handle> {
+        self.handles.iter()
+    }
+
+    // The process this one is blocked on, if any.
+    pub fn waiting_for<'a>(&self) -> Option<&'a Process> {
+        if self.waiting_for.is_null() { None } else { Some(unsafe { &*self.waiting_for }) }
+    }
+
+    pub fn debugger<'a>(&self) -> Option<&'a mut Process> {
+        if self.debugger.is_null() { None } else { Some(unsafe { &mut *self.debugger }) }
+    }
+
+    pub fn set_debugger(&mut self, debugger : *mut Process, handle : u64) {
+        self.debugger = debugger;
+        self.debugger_handle = handle;
+    }
+
     pub fn find_handle<'a>(&mut self, id : u64) -> Option<&'a mut Handle> {
         let res = self.handles.find(id);
         match res {
@@ -332,24 +436,47 @@ impl Process {
     }
 
     pub fn dump(&self) {
-        write("proc ");
-        con::writePtr(self);
-        write(" f=");
-        con::writeHex(self.flags);
-        write(":\n");
+        kprint!("proc {} {:p} f={:#x}", self.id, self, self.flags);
+        for (i, name) in flag_names.iter().enumerate() {
+            if self.flags & (1 << i) != 0 {
+                kprint!(" {}", name);
+            }
+        }
+        kprintln!(":");
+        if !self.waiting_for.is_null() {
+            kprintln!("  waiting for proc {}", unsafe { (*self.waiting_for).id });
+        }
+        if self.is(PFault) {
+            kprintln!("  fault_addr {:#x}", self.fault_addr);
+        }
+        if !self.debugger.is_null() {
+            kprintln!("  debugged by proc {} as handle {}", unsafe { (*self.debugger).id }, self.debugger_handle);
+        }
 
         for (id,h) in self.handles.iter() {
-            write("  handle ");
-            con::writeUInt(id);
-            write(" -> proc ");
-            con::writeMutPtr(h.process());
-            con::newline();
+            kprint!("  handle {} -> proc {} {:p}", id, h.process().id, h.process());
+            match h.other {
+                Some(other) => kprint!(" as {}", unsafe { (*other).id() }),
+                None => (),
+            }
+            if h.pulses != 0 {
+                kprint!(" pulses {:#x}", h.pulses);
+            }
+            kprintln!();
         }
 
         for p in self.waiters.iter() {
-            write("  waiter ");
-            con::writeMutPtr(p);
-            con::newline();
+            kprintln!("  waiter proc {} {:p}", p.id, p);
         }
     }
+
+    // The saved user-mode registers.
+    pub fn dump_regs(&self) {
+        let r = &self.regs;
+        kprintln!("  rip={:#018x} rflags={:#x} cr3={:#x}", self.rip, self.rflags, self.cr3);
+        kprintln!("  rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}", r.rax, r.rbx, r.rcx, r.rdx);
+        kprintln!("  rsi={:#018x} rdi={:#018x} rbp={:#018x} rsp={:#018x}", r.rsi, r.rdi, r.rbp, r.rsp);
+        kprintln!("  r8 ={:#018x} r9 ={:#018x} r10={:#018x} r11={:#018x}", r.r8, r.r9, r.r10, r.r11);
+        kprintln!("  r12={:#018x} r13={:#018x} r14={:#018x} r15={:#018x}", r.r12, r.r13, r.r14, r.r15);
+    }
 }
@@ -0,0 +1,239 @@
+// 16550 UART driver for kernel output and the kernel monitor's input. Output
+// is polled, and so is input: the kernel checks for received data when idle
+// and on interrupts. On COM1 and COM2, received data raises the port's IRQ
+// too, so input gets through while processes keep the CPU busy.
+use x86::inb;
+use x86::outb;
+
+pub const COM1 : u16 = 0x3f8;
+pub const COM2 : u16 = 0x2f8;
+
+// Register offsets from the base port
+const DATA : u16 = 0;
+const IER : u16 = 1;
+const FCR : u16 = 2;
+const LCR : u16 = 3;
+const MCR : u16 = 4;
+const LSR : u16 = 5;
+const SCRATCH : u16 = 7;
+// With LCR_DLAB set, DATA and IER hold the divisor
+const DLL : u16 = 0;
+const DLH : u16 = 1;
+
+const LCR_8N1 : u8 = 0x03;
+const LCR_DLAB : u8 = 0x80;
+const LSR_DATA_READY : u8 = 0x01;
+const LSR_THR_EMPTY : u8 = 0x20;
+const IER_RX : u8 = 0x01;
+const MCR_DTR_RTS : u8 = 0x03;
+// Connects the UART's interrupt line on PCs
+const MCR_OUT2 : u8 = 0x08;
+const MCR_LOOPBACK : u8 = 0x10;
+
+// Spins to wait for a byte to come back in loopback mode
+const probe_spins : u32 = 100000;
+
+const max_line : usize = 80;
+
+struct Serial {
+    // Base I/O port, 0 if there's no serial output
+    port : u16,
+    line : [u8; max_line],
+    line_len : usize,
+    // Last character was a CR, so ignore a following LF
+    cr : bool,
+}
+
+static mut serial : Serial = Serial { port : 0, line : [0; max_line], line_len : 0, cr : false };
+
+// IRQ lines (on the master PIC) of the ports with input IRQs enabled
+static mut irq_lines : u8 = 0;
+
+// Check that there's a UART at port: the scratch register keeps its value and
+// data sent in loopback mode comes back.
+fn probe(port : u16) -> bool {
+    unsafe {
+        outb(port + SCRATCH, 0x5a);
+        if inb(port + SCRATCH) != 0x5a {
+            return false;
+        }
+        outb(port + MCR, MCR_LOOPBACK);
+        // Drop anything received before
+        while inb(port + LSR) & LSR_DATA_READY != 0 {
+            inb(port + DATA);
+        }
+        outb(port + DATA, 0xa5);
+        let mut ok = false;
+        for _ in 0..probe_spins {
+            if inb(port + LSR) & LSR_DATA_READY != 0 {
+                ok = inb(port + DATA) == 0xa5;
+                break;
+            }
+        }
+        outb(port + MCR, MCR_DTR_RTS);
+        ok
+    }
+}
+
+// Program the UART at port, returns false if there's no UART there. Used for
+// the console's port by init, and for other ports by e.g. the GDB stub.
+pub fn setup(port : u16, baud : u32) -> bool {
+    if baud == 0 || baud > 115200 || !probe(port) {
+        return false;
+    }
+    let divisor = 115200 / baud;
+    unsafe {
+        // No interrupts, we poll
+        outb(port + IER, 0);
+        outb(port + LCR, LCR_DLAB);
+        outb(port + DLL, divisor as u8);
+        outb(port + DLH, (divisor >> 8) as u8);
+        outb(port + LCR, LCR_8N1);
+        // Enable and clear FIFOs, 14-byte receive threshold
+        outb(port + FCR, 0xc7);
+        outb(port + MCR, MCR_DTR_RTS);
+    }
+    true
+}
+
+fn irq_line(port : u16) -> Option<u8> {
+    match port {
+        COM1 => Some(4),
+        COM2 => Some(3),
+        _ => None,
+    }
+}
+
+// Raise the port's IRQ when data is received on a port set up with setup, if
+// it's a port with a known IRQ line. The kernel handles those IRQs itself, see
+// is_input_irq.
+pub fn enable_input_irq(port : u16) {
+    let line = match irq_line(port) {
+        Some(line) => line,
+        None => return,
+    };
+    unsafe {
+        irq_lines |= 1 << line;
+        outb(port + IER, IER_RX);
+        outb(port + MCR, MCR_DTR_RTS | MCR_OUT2);
+        outb(0x21, inb(0x21) & !(1 << line));
+    }
+}
+
+fn disable_input_irq(port : u16) {
+    let line = match irq_line(port) {
+        Some(line) => line,
+        None => return,
+    };
+    unsafe {
+        irq_lines &= !(1 << line);
+        outb(port + IER, 0);
+        outb(port + MCR, MCR_DTR_RTS);
+        outb(0x21, inb(0x21) | (1 << line));
+    }
+}
+
+// Whether vec is the IRQ of a port with input IRQs enabled.
+pub fn is_input_irq(vec : u8) -> bool {
+    vec >= 32 && vec < 40 && unsafe { irq_lines } & (1 << (vec - 32)) != 0
+}
+
+// Acknowledge an input IRQ, after polling the port.
+pub fn end_input_irq() {
+    unsafe { outb(0x20, 0x20); }
+}
+
+// Set up the console's UART at port (0 to turn serial output off). Returns
+// false if there's no UART there.
+pub fn init(port : u16, baud : u32) -> bool {
+    unsafe {
+        if serial.port != 0 {
+            disable_input_irq(serial.port);
+        }
+        serial.port = 0;
+    }
+    if port == 0 {
+        return true;
+    }
+    if !setup(port, baud) {
+        return false;
+    }
+    unsafe { serial.port = port; }
+    enable_input_irq(port);
+    true
+}
+
+pub fn port() -> u16 {
+    unsafe { serial.port }
+}
+
+// Send a byte on a port set up with setup.
+pub fn send_to(port : u16, b : u8) {
+    unsafe {
+        while inb(port + LSR) & LSR_THR_EMPTY == 0 {}
+        outb(port + DATA, b);
+    }
+}
+
+// A received byte on a port set up with setup, if there is one.
+pub fn recv_from(port : u16) -> Option<u8> {
+    unsafe {
+        if inb(port + LSR) & LSR_DATA_READY == 0 {
+            None
+        } else {
+            Some(inb(port + DATA))
+        }
+    }
+}
+
+fn send(b : u8) {
+    send_to(port(), b);
+}
+
+pub fn putc(c : char) {
+    if port() == 0 {
+        return;
+    }
+    if c == '\n' {
+        send(b'\r');
+    }
+    send(c as u8);
+}
+
+fn getc() -> Option<u8> {
+    recv_from(port())
+}
+
+// Read what has been received so far, with simple line editing and echo.
+// Calls f with each completed line (without the line terminator).
+pub fn poll<F : FnMut(&[u8])>(mut f : F) {
+    if port() == 0 {
+        return;
+    }
+    let s = unsafe { &mut serial };
+    while let Some(b) = getc() {
+        let cr = s.cr;
+        s.cr = b == b'\r';
+        match b {
+            b'\n' if cr => (),
+            b'\r' | b'\n' => {
+                putc('\n');
+                f(&s.line[..s.line_len]);
+                s.line_len = 0;
+            },
+            // Backspace and DEL
+            8 | 127 => if s.line_len > 0 {
+                s.line_len -= 1;
+                send(8);
+                send(b' ');
+                send(8);
+            },
+            32 ..= 126 => if s.line_len < max_line {
+                s.line[s.line_len] = b;
+                s.line_len += 1;
+                send(b);
+            },
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+; vim:filetype=nasm:
+;
+; 32-bit entry from the boot loader, for multiboot 1 and multiboot 2: save
+; the boot info and the boot loader magic, set up page tables and enter long
+; mode at start64. Memory for the kernel to use starts after the kernel; the
+; boot info and the modules get reserved in the frame allocator (see mem.rs).
+
+%define kernel_start 0x100000
+; Keep in sync with linker.ld
+%define high_org 0xffffffffc0000000
+
+%define mboot_magic 0x1badb002
+; Memory info, address fields
+%define mboot_flags 0x00010002
+%define mboot_loader_magic 0x2badb002
+
+%define mboot2_magic 0xe85250d6
+%define mboot2_loader_magic 0x36d76289
+%define mboot2_header_size (mboot2_header_end - mboot2_header)
+
+; Offsets into the multiboot info
+%define mbi_flags 0
+%define mbi_cmdline 16
+%define mbi_mods_count 20
+%define mbi_mods_addr 24
+%define mbi_mmap_length 44
+%define mbi_mmap_addr 48
+; Copied up to and including the framebuffer info
+%define mbi_size 116
+
+%define mbi_flag_cmdline 0x4
+%define mbi_flag_mods 0x8
+%define mbi_flag_mmap 0x40
+
+; The GDT below must match x86::seg and user_code_seg in syscall.asm
+code64_seg equ 0x18
+
+extern start64
+; From linker.ld: physical end of the loaded image and of the bss
+extern section.data.end
+extern section.bss.end
+
+global start32_mboot
+global start32_mboot2
+global gdtr
+global mbi_pointer
+global memory_start
+global mbi_magic
+global kernel_pdp
+
+bits 32
+section .text
+
+mboot_header:
+	dd	mboot_magic
+	dd	mboot_flags
+	dd	-(mboot_magic + mboot_flags) & 0xffffffff
+	; header_addr, load_addr, load_end_addr, bss_end_addr, entry_addr
+	dd	kernel_start
+	dd	kernel_start
+	dd	section.data.end
+	dd	section.bss.end
+	dd	kernel_start + (start32_mboot - mboot_header)
+
+align 8
+mboot2_header:
+	dd	mboot2_magic
+	dd	0 ; i386
+	dd	mboot2_header_size
+	dd	-(mboot2_magic + mboot2_header_size) & 0xffffffff
+	; Address tag
+	dw	2, 0
+	dd	24
+	dd	kernel_start + (mboot2_header - mboot_header)
+	dd	kernel_start
+	dd	section.data.end
+	dd	section.bss.end
+	; Entry address tag, padded to 8 bytes
+	dw	3, 0
+	dd	12
+	dd	kernel_start + (start32_mboot2 - mboot_header)
+	dd	0
+	; Framebuffer tag (optional, any mode), for fbcon.rs. Padded to 8 bytes.
+	dw	5, 1
+	dd	20
+	dd	0, 0, 0
+	dd	0
+	; End tag
+	dw	0, 0
+	dd	8
+mboot2_header_end:
+
+; Multiboot 2: the tag list stays where the boot loader put it.
+start32_mboot2:
+	o16 lgdt [gdtr]
+	cmp	eax, mboot2_loader_magic
+	jne	$
+	mov	[mbi_magic], eax
+	mov	[mbi_pointer], ebx
+	jmp	start32
+
+; Multiboot 1: the info and everything it points to gets copied to after the
+; kernel and the modules, since the boot loader may have put it anywhere.
+start32_mboot:
+	o16 lgdt [gdtr]
+	cmp	eax, mboot_loader_magic
+	jne	$
+	mov	[mbi_magic], eax
+
+find_copy_address:
+	mov	ebp, section.bss.end
+find_mod_ends:
+	mov	esi, [ebx + mbi_mods_addr]
+	mov	ecx, [ebx + mbi_mods_count]
+.mods_loop:
+	jecxz	.mods_loop_out
+	mov	eax, [esi + 4]
+	cmp	eax, ebp
+	jb	.below
+	mov	ebp, eax
+.below:
+	add	esi, 16
+	loop	.mods_loop
+.mods_loop_out:
+	add	ebp, 0xfff
+	and	bp, 0xf000
+
+copy_multiboot_info:
+	mov	[mbi_pointer], ebp
+	mov	esi, ebx
+	mov	edi, ebp
+	mov	ecx, mbi_size / 4
+	rep movsd
+
+	; Without a memory map, mem.rs uses mem_lower/mem_upper or probes
+	test	byte [ebx + mbi_flags], mbi_flag_mmap
+	jz	.no_mmap
+	mov	esi, [ebx + mbi_mmap_addr]
+	mov	[ebp + mbi_mmap_addr], edi
+	mov	ecx, [ebx + mbi_mmap_length]
+	rep movsb
+.no_mmap:
+
+	test	byte [ebx + mbi_flags], mbi_flag_cmdline
+	jz	.no_cmdline
+	mov	esi, [ebx + mbi_cmdline]
+	mov	[ebp + mbi_cmdline], edi
+.strcpy_loop:
+	lodsb
+	stosb
+	test	al, al
+	jnz	.strcpy_loop
+.no_cmdline:
+
+copy_modules:
+	test	byte [ebx + mbi_flags], mbi_flag_mods
+	jz	.no_modules
+	mov	esi, [ebx + mbi_mods_addr]
+	mov	[ebp + mbi_mods_addr], edi
+	mov	ecx, [ebx + mbi_mods_count]
+	jecxz	.no_modules
+	lea	ecx, [ecx * 4]
+	rep movsd
+
+	; esp = original module, edx = copy, for copying the strings
+	mov	esp, [ebx + mbi_mods_addr]
+	mov	edx, [ebp + mbi_mods_addr]
+	mov	ecx, [ebx + mbi_mods_count]
+.loop:
+	mov	esi, [esp + 8]
+	mov	[edx + 8], edi
+.cp_str:
+	lodsb
+	test	al, al
+	stosb
+	jnz	.cp_str
+	add	esp, 16
+	add	edx, 16
+	loop	.loop
+.no_modules:
+
+start32:
+	mov	eax, section.bss.end + 0xfff
+	and	eax, ~0xfff
+	mov	[memory_start], eax
+
+	; Remap and mask the PICs
+	mov	al, 0x11
+	out	0x20, al
+	out	0xa0, al
+	mov	al, 0x20
+	out	0x21, al
+	mov	al, 0x28
+	out	0xa1, al
+	mov	al, 4
+	out	0x21, al
+	mov	al, 2
+	out	0xa1, al
+	mov	al, 1
+	out	0x21, al
+	out	0xa1, al
+	mov	al, 0xff
+	out	0x21, al
+	out	0xa1, al
+
+	; Clear the screen and show that we got here
+	mov	ebx, 0xb8000
+	mov	edi, ebx
+	xor	eax, eax
+	mov	ecx, 80 * 25 / 2
+	rep stosd
+	mov	word [ebx], 0x0f00 | 'P'
+
+	; pml4[0] -> pdp_low, pml4[511] -> kernel_pdp
+	mov	edi, pml4
+	mov	eax, pdp_low + 7
+	stosd
+	xor	eax, eax
+	mov	ecx, 1023
+	rep stosd
+	mov	dword [edi - 8], kernel_pdp + 3
+
+	mov	eax, pd_low + 7
+	stosd
+	xor	eax, eax
+	mov	ecx, 1023
+	rep stosd
+
+	mov	eax, pt_low + 7
+	stosd
+	xor	eax, eax
+	mov	ecx, 1023
+	rep stosd
+
+	xor	eax, eax
+	mov	ecx, 1024
+	rep stosd
+
+	; Identity map the first 4 pages of the kernel (read-only) for the jump
+	; to 64-bit code.
+	sub	edi, 4096 - (kernel_start >> 12) * 8
+	mov	eax, kernel_start | 1
+	mov	cl, 4
+.map_kernel_pages:
+	stosd
+	add	di, 4
+	add	ax, 0x1000
+	loop	.map_kernel_pages
+
+	mov	edi, kernel_pdp
+	xor	eax, eax
+	mov	ecx, 1024
+	rep stosd
+	mov	dword [edi - 8], kernel_pd + 3
+
+	; The first 1GB of physical memory at high_org, in 2MB pages
+.fill_pd:
+	mov	edi, kernel_pd
+	mov	eax, 0x83
+	mov	ecx, 512
+.loop:
+	stosd
+	add	edi, 4
+	add	eax, 0x200000
+	loop	.loop
+
+	; PAE, PGE and friends
+	mov	eax, 0x7e0
+	mov	cr4, eax
+	mov	edx, pml4
+	mov	cr3, edx
+	; EFER.LME
+	mov	ecx, 0xc0000080
+	rdmsr
+	or	eax, 0x100
+	wrmsr
+	; PG and MP, clear EM
+	mov	eax, cr0
+	or	eax, 0x80000002
+	and	al, ~4
+	mov	cr0, eax
+	jmp	code64_seg:.trampoline
+
+bits 64
+.trampoline:
+	mov	rsp, boot_stack_end + high_org
+	jmp	start64
+
+%define tss_base (high_org + kernel_start + (tss - $$))
+
+align 16
+gdt_start:
+	dq	0
+	; 0x08: 32-bit code and data
+	dq	0x00cf9a000000ffff
+	dq	0x00cf92000000ffff
+	; 0x18: 64-bit code and data
+	dq	0x00209a0000000000
+	dq	0x0000920000000000
+	; 0x28: TSS
+	dw	tss_end - tss
+	dw	tss_base & 0xffff
+	db	(tss_base >> 16) & 0xff
+	db	0x89
+	db	0
+	db	(tss_base >> 24) & 0xff
+	dd	tss_base >> 32
+	dd	0
+	; 0x38: user 32-bit code and data, 64-bit code and data
+	dq	0x00cffa000000ffff
+	dq	0x00cff2000000ffff
+	dq	0x0020fa0000000000
+	dq	0x0000f20000000000
+gdt_end:
+gdt_offset equ gdt_start - $$
+
+tss:
+	dd	0
+	; rsp0
+	dq	boot_stack_end + high_org
+	times	0x24 - ($ - tss) db 0
+	; ist1, for double faults
+	dq	df_stack_end + high_org
+	times	0x66 - ($ - tss) db 0
+	; I/O map base: none
+	dw	tss_end - tss
+tss_end:
+
+section .rodata
+
+gdtr:
+	dw	gdt_end - gdt_start - 1
+	dq	high_org + kernel_start + gdt_offset
+
+section .bss nobits alloc noexec write align=4096
+
+; Boot page tables and stack. pml4 to pt_low must stay in this order, they get
+; filled in one go.
+pml4:
+	resb	4096
+pdp_low:
+	resb	4096
+pd_low:
+	resb	4096
+pt_low:
+	resb	4096
+boot_stack:
+	resb	4096
+boot_stack_end:
+kernel_pdp:
+	resb	4096
+kernel_pd:
+	resb	4096
+df_stack:
+	resb	4096
+df_stack_end:
+
+; Physical address of the copied multiboot 1 info or the multiboot 2 tag list
+mbi_pointer:
+	resd	1
+; Physical address of the first page after the kernel
+memory_start:
+	resd	1
+; The boot loader magic from eax, for which boot protocol was used
+mbi_magic:
+	resd	1
@@ -9,6 +9,8 @@ extern {
     //static orig_mbi_pointer : u32;
     static gdtr : x86::Gdtr;
     static kernel_pdp : [u64; 512];
+    // The boot loader magic, so we know which boot protocol was used
+    static mbi_magic : u32;
 }
 
 pub static kernel_base : u64 = -(1i64 << 30) as u64;
@@ -30,7 +32,16 @@ pub fn PhysAddrRef<'a, T>(addr : u64) -> &'a T {
 }
 
 pub fn MultiBootInfo() -> &'static mboot::Info {
-    unsafe { PhysAddrRef(*HighAddr(&mbi_pointer) as u64) }
+    PhysAddrRef(BootInfoAddr())
+}
+
+// Physical address of the multiboot 1 info or multiboot 2 tag list
+pub fn BootInfoAddr() -> u64 {
+    unsafe { *HighAddr(&mbi_pointer) as u64 }
+}
+
+pub fn BootMagic() -> u32 {
+    unsafe { *HighAddr(&mbi_magic) }
 }
 
 pub fn MemoryStart() -> u64 {
@@ -51,7 +62,7 @@ pub fn Gdtr() -> &'static x86::Gdtr {
 //  PhysAddr(orig_mbi_pointer as uint)
 //}
 
-// TOOD: Remove hardcoded lower-half mappings from start32.o
+// TOOD: Remove hardcoded lower-half mappings from start32.asm
 pub fn CleanPageMappings() {
 }
 
@@ -0,0 +1,167 @@
+// Per-CPU event counters: syscalls, IPC, page faults, context switches and
+// IRQs. They are always on and cost an increment each. With stats=cycles on
+// the command line, the time (in TSC cycles) from entering the kernel for a
+// syscall or page fault until it switches to a process or goes idle is added
+// up as well. Read with the INFO syscall, the monitor's stats command, or on
+// the memory status line with stats=line.
+use core::fmt::Write;
+
+use cmdline;
+use con::Console;
+use syscall::nr;
+use x86::rdtsc;
+
+// Kinds of page fault, for Counters::faults
+#[derive(Clone, Copy)]
+pub enum Fault {
+    // Anonymous memory, allocated and zeroed by the kernel
+    Anon = 0,
+    // Physical memory (modules, MMIO)
+    Phys = 1,
+    // Sent to a pager process with the PFAULT syscall
+    Pager = 2,
+}
+
+// Slots in Counters::syscalls. Fixed, since it's part of the INFO record.
+pub const syscall_slots : usize = 32;
+
+// Also the INFO syscall record, so fields only get added at the end.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Counters {
+    // Syscalls by number, with all IPC sends and calls (nr >= USER) in the
+    // last slot
+    pub syscalls : [u64; syscall_slots],
+    // Messages delivered
+    pub transfers : u64,
+    // Sends that blocked because the recipient wasn't receiving from us
+    pub blocks : u64,
+    // Page faults by Fault kind
+    pub faults : [u64; 3],
+    pub switches : u64,
+    // IRQs by vector, from 32
+    pub irqs : [u64; 17],
+    // With stats=cycles, TSC cycles spent in syscalls and page faults
+    pub syscall_cycles : u64,
+    pub fault_cycles : u64,
+}
+
+#[derive(PartialEq)]
+enum Path {
+    None,
+    Syscall,
+    Fault,
+}
+
+// The CPU whose counters are on the status line, once it's set up
+static mut status_cpu : *const PerCpu = 0 as *const PerCpu;
+
+pub struct PerCpu {
+    pub count : Counters,
+    // The path being timed and when it started
+    path : Path,
+    start : u64,
+}
+
+impl PerCpu {
+    pub fn new() -> PerCpu {
+        PerCpu {
+            count : Counters {
+                syscalls : [0; syscall_slots],
+                transfers : 0,
+                blocks : 0,
+                faults : [0; 3],
+                switches : 0,
+                irqs : [0; 17],
+                syscall_cycles : 0,
+                fault_cycles : 0,
+            },
+            path : Path::None,
+            start : 0,
+        }
+    }
+
+    fn enter(&mut self, path : Path) {
+        if self.path == Path::None && cmdline::get().stat_cycles {
+            self.path = path;
+            self.start = rdtsc();
+        }
+    }
+
+    pub fn syscall(&mut self, n : u64) {
+        let i = if n < nr::USER { n as usize } else { syscall_slots - 1 };
+        self.count.syscalls[i] += 1;
+        self.enter(Path::Syscall);
+    }
+
+    // A fault inside a syscall (PFAULT) is timed as part of the syscall.
+    pub fn fault(&mut self, kind : Fault) {
+        self.count.faults[kind as usize] += 1;
+        self.enter(Path::Fault);
+    }
+
+    pub fn irq(&mut self, vec : u8) {
+        self.count.irqs[(vec - 32) as usize] += 1;
+    }
+
+    // Leaving the kernel, to a process or to idle.
+    pub fn leave(&mut self) {
+        let cycles = match self.path {
+            Path::None => return,
+            Path::Syscall => &mut self.count.syscall_cycles,
+            Path::Fault => &mut self.count.fault_cycles,
+        };
+        *cycles += rdtsc() - self.start;
+        self.path = Path::None;
+    }
+
+    pub fn print(&self) {
+        let c = &self.count;
+        kprint!("syscalls:");
+        for (n, &count) in c.syscalls[..syscall_slots - 1].iter().enumerate() {
+            if count != 0 {
+                kprint!(" {}={}", n, count);
+            }
+        }
+        kprint!(" ipc={}", c.syscalls[syscall_slots - 1]);
+        kprintln!();
+        kprintln!("ipc: {} transfers, {} blocks", c.transfers, c.blocks);
+        kprintln!("page faults: {} anon, {} phys, {} pager", c.faults[0], c.faults[1], c.faults[2]);
+        kprintln!("context switches: {}", c.switches);
+        kprint!("irqs:");
+        for (i, &count) in c.irqs.iter().enumerate() {
+            if count != 0 {
+                kprint!(" {}={}", i + 32, count);
+            }
+        }
+        kprintln!();
+        if cmdline::get().stat_cycles {
+            kprintln!("cycles: {} in syscalls, {} in page faults", c.syscall_cycles, c.fault_cycles);
+        }
+    }
+
+    // Show these counters on the status line (with stats=line).
+    pub fn start(&self) {
+        if cmdline::get().stat_line {
+            unsafe { status_cpu = self; }
+        }
+    }
+
+    fn stat_(&self, con : &mut Console) {
+        let c = &self.count;
+        let syscalls = c.syscalls.iter().fold(0, |a, &n| a + n);
+        let faults = c.faults.iter().fold(0, |a, &n| a + n);
+        let irqs = c.irqs.iter().fold(0, |a, &n| a + n);
+        let _ = write!(con, " sys:{} ipc:{}/{} pf:{} sw:{} irq:{}",
+            syscalls, c.transfers, c.blocks, faults, c.switches, irqs);
+    }
+}
+
+// Add the counters to the memory status line, if they go there.
+pub fn stat_line(con : &mut Console) {
+    unsafe {
+        if !status_cpu.is_null() {
+            (*status_cpu).stat_(con);
+        }
+    }
+}
@@ -306,6 +306,16 @@ proc handle_irq_generic, NOSECTION
 	jmp	irq_entry
 
 .kernel_fault:
+	; Debug traps in the kernel go to the debugger and then back to where
+	; they came from.
+	cmp	edi, 1
+	je	.kernel_debug
+	cmp	edi, 3
+	je	.kernel_debug
+	; Other exceptions in the kernel are fatal
+	cmp	edi, 32
+	jb	.kernel_exception
+
 	zero	eax
 	mov	rax, [gs:rax + gseg.proc]
 	test	rax, rax
@@ -319,6 +329,81 @@ proc handle_irq_generic, NOSECTION
 	mov	rdi, [rsp + 24]
 	jmp	.irq_entry
 
+.kernel_debug:
+	; saved_rax, saved_rdi, saved_rsi, vector, rip, cs, rflags, rsp, ss
+	; (no error code for #DB and #BP). Complete the frame with the other
+	; registers, see x86::idt::KernelFrame.
+	push	rbx
+	push	rcx
+	push	rdx
+	push	rbp
+	push	r8
+	push	r9
+	push	r10
+	push	r11
+	push	r12
+	push	r13
+	push	r14
+	push	r15
+	mov	rdi, rsp
+	; 21 qwords pushed since the (aligned) start of the frame
+	sub	rsp, 8
+	extern	kernel_debug_trap
+	call	kernel_debug_trap
+	add	rsp, 8
+	pop	r15
+	pop	r14
+	pop	r13
+	pop	r12
+	pop	r11
+	pop	r10
+	pop	r9
+	pop	r8
+	pop	rbp
+	pop	rdx
+	pop	rcx
+	pop	rbx
+	pop	rax
+	pop	rdi
+	pop	rsi
+	add	rsp, 8 ; vector
+	iretq
+
+.kernel_exception:
+	; saved_rax, saved_rdi, saved_rsi, vector, [error], rip, cs, rflags, rsp, ss
+	; Take out the error code (if any) and move the rest up over it, to get
+	; the same frame as for debug traps. We don't return from here.
+	zero	esi
+	test	esp, 8
+	jnz	.kernel_no_err
+	mov	rsi, [rsp + 32]
+	mov	rax, [rsp + 24]
+	mov	[rsp + 32], rax
+	mov	rax, [rsp + 16]
+	mov	[rsp + 24], rax
+	mov	rax, [rsp + 8]
+	mov	[rsp + 16], rax
+	mov	rax, [rsp]
+	mov	[rsp + 8], rax
+	add	rsp, 8
+.kernel_no_err:
+	push	rbx
+	push	rcx
+	push	rdx
+	push	rbp
+	push	r8
+	push	r9
+	push	r10
+	push	r11
+	push	r12
+	push	r13
+	push	r14
+	push	r15
+	mov	rdi, rsp
+	and	rsp, ~15
+	extern	kernel_fault
+	call	kernel_fault
+
 ; slowret: all registers are currently unknown, load *everything* from process
 ; (in rdi), then iretq
 proc slowret, NOSECTION
@@ -344,8 +429,23 @@ endproc
 
 endproc
 
-handler_NM_stub stub 7
-gfunc handler_NM_stub
-handler_PF_stub stub 14
-gfunc handler_PF_stub
+; Stubs for exceptions 0-31. The ones in EXC_ERR_MASK get an error code from
+; the CPU, handle_irq_generic tells them apart by the stack alignment.
+%assign vec 0
+%rep 32
+exc_handler_ %+ vec:
+	stub vec
+%assign vec vec + 1
+%endrep
+
+section .rodata.exc_handlers, noexec nowrite
+
+align 8
+global exc_handlers
+exc_handlers:
+%assign vec 0
+%rep 32
+	dq	exc_handler_ %+ vec
+%assign vec vec + 1
+%endrep
 
@@ -1,28 +1,30 @@
+use core::cmp::min;
+use core::slice;
+
 use aspace::mapflag;
 use aspace::MapFlag;
 use con;
-use con::write;
 use cpu;
+use debug;
+use bootinfo;
 use dump_runqueue;
+use events;
+use free;
+use image::Image;
+use info;
+use initrd;
+use klog;
+use new_proc;
+use log;
+use malloc;
+use max_args_len;
 use process;
 use process::Handle;
 use process::Process;
 use start32::kernel_base;
+use stats;
 use util::abort;
 
-static log_syscall : bool = false;
-static log_unknown_syscall : bool = false;
-static log_transfer_message : bool = false;
-static log_portio : bool = false;
-static log_hmod : bool = false;
-static log_map : bool = false;
-static log_pfault : bool = false;
-static log_grant : bool = false;
-
-static log_recv : bool = false;
-static log_ipc : bool = false;
-static log_pulse : bool = false;
-
 pub mod nr {
     #![allow(dead_code)]
     pub const RECV : u64 = 0;
@@ -35,8 +37,15 @@ pub mod nr {
     pub const PORTIO : u64 = 7;
     pub const GRANT : u64 = 8;
     pub const PULSE : u64 = 9;
+    pub const LOGCTL : u64 = 10;
+    pub const BOOTINFO : u64 = 11;
+    pub const KLOG : u64 = 12;
+    pub const TRACE : u64 = 13;
+    pub const INFO : u64 = 14;
+    pub const DEBUG : u64 = 15;
+    pub const WRITEBUF : u64 = 16;
 
-    pub const USER : u64 = 16;
+    pub const USER : u64 = 17;
 
     pub const MSG_MASK : u64 = 0xff;
     pub const MSG_KIND_MASK : u64 = 0x300;
@@ -48,6 +57,64 @@ pub mod nr {
     }
 }
 
+// Error results, returned in rax by syscalls that can fail.
+pub mod err {
+    #![allow(dead_code)]
+    pub const EPERM : u64 = -1i64 as u64;
+    pub const EINVAL : u64 = -2i64 as u64;
+    pub const EBUSY : u64 = -3i64 as u64;
+    // Bad user pointer
+    pub const EFAULT : u64 = -4i64 as u64;
+}
+
+// Operations for the KLOG syscall
+pub mod klog_op {
+    pub const MAP : u64 = 0;
+    pub const WAIT : u64 = 1;
+}
+
+// Names and argument counts of the syscalls, for tracing
+const syscall_names : [(&'static str, usize); 17] = [
+    ("recv", 1), ("map", 5), ("pfault", 3), ("unmap", 0), ("hmod", 3),
+    ("newproc", 5), ("write", 1), ("portio", 3), ("grant", 3), ("pulse", 2),
+    ("logctl", 2), ("bootinfo", 1), ("klog", 2), ("trace", 2), ("info", 5),
+    ("debug", 5), ("writebuf", 2),
+];
+
+// Print a trace record if p is traced.
+macro_rules! trace {
+    ($p:expr, $($arg:tt)*) => (if $p.is(process::Traced) {
+        kprint!("trace [{}] ", $p.id);
+        kprintln!($($arg)*);
+    });
+}
+
+fn handle_target(p : &mut Process, id : u64) -> u32 {
+    match p.find_handle(id) {
+        Some(h) => h.process().id,
+        None => 0,
+    }
+}
+
+fn trace_syscall(p : &mut Process, nr : u64, args : &[u64; 6]) {
+    use syscall::nr::*;
+    if nr >= USER {
+        let kind = if nr & MSG_KIND_MASK == MSG_KIND_CALL { "call" } else { "send" };
+        let target = handle_target(p, args[0]);
+        trace!(p, "{} {:#x} to handle {:#x} (proc {}) args {:#x} {:#x} {:#x} {:#x} {:#x}",
+            kind, nr & MSG_MASK, args[0], target, args[1], args[2], args[3], args[4], args[5]);
+    } else if (nr as usize) < syscall_names.len() {
+        let (name, nargs) = syscall_names[nr as usize];
+        kprint!("trace [{}] {}(", p.id, name);
+        for i in 0..nargs {
+            kprint!("{}{:#x}", if i > 0 { ", " } else { "" }, args[i]);
+        }
+        kprintln!(")");
+    } else {
+        trace!(p, "syscall {} args {:#x} {:#x} {:#x}", nr, args[0], args[1], args[2]);
+    }
+}
+
 // Note: tail-called from the syscall code, "return" by switching to a process.
 #[no_mangle]
 pub fn syscall(
@@ -64,9 +131,14 @@ pub fn syscall(
 ) -> ! {
     use syscall::nr::*;
 
+    cpu().stats.syscall(nr);
     let p = cpu().get_process().unwrap();
     p.unset(process::Running);
     p.set(process::FastRet);
+    cpu().events.record(events::kind::SYSCALL, p.id, nr, arg0);
+    if p.is(process::Traced) {
+        trace_syscall(p, nr, &[arg0, arg1, arg2, arg3, arg4, arg5]);
+    }
 
     match nr {
     RECV => ipc_recv(p, arg0),
@@ -74,7 +146,7 @@ pub fn syscall(
     PFAULT => syscall_pfault(p, arg1, arg2 as MapFlag), // arg0 is always 0
     // unmap
     HMOD => syscall_hmod(p, arg0, arg1, arg2),
-    //newproc
+    NEWPROC => syscall_newproc(p, arg0, arg1, arg2, arg3, arg4),
     WRITE => {
         con::putc(arg0 as u8 as char);
         syscall_return(p, 0);
@@ -82,6 +154,13 @@ pub fn syscall(
     PORTIO => syscall_portio(p, arg0 as u16, arg1 as u8, arg2 as u32),
     GRANT => syscall_grant(p, arg0, arg1, arg2 as MapFlag),
     PULSE => syscall_pulse(p, arg0, arg1),
+    LOGCTL => syscall_logctl(p, arg0, arg1),
+    BOOTINFO => syscall_bootinfo(p, arg0),
+    KLOG => syscall_klog(p, arg0, arg1),
+    TRACE => syscall_trace(p, arg0, arg1),
+    INFO => syscall_info(p, arg0, arg1, arg2, arg3, arg4),
+    DEBUG => syscall_debug(p, arg0, arg1, arg2, arg3, arg4),
+    WRITEBUF => syscall_writebuf(p, arg0, arg1),
     _ if nr >= USER => {
         match nr & MSG_KIND_MASK {
             MSG_KIND_CALL => ipc_call(p, nr, arg0, arg1, arg2, arg3, arg4, arg5),
@@ -90,12 +169,8 @@ pub fn syscall(
         }
     },
     _ => {
-        if log_syscall || log_unknown_syscall {
-            write("syscall! nr=");
-            con::writeUInt(nr);
-            write(" from process ");
-            con::writeMutPtr(p);
-            con::newline();
+        if log::on(log::SYSCALL | log::UNKNOWN_SYSCALL) {
+            kprintln!("syscall! nr={} from process {:p}", nr, p);
         }
         abort("Unhandled syscall")
     },
@@ -111,20 +186,12 @@ pub fn syscall(
 #[inline(never)]
 fn ipc_call(p : &mut Process, msg : u64, to : u64, arg1: u64, arg2: u64,
     arg3: u64, arg4: u64, arg5: u64) {
-    let log = log_ipc && to != 3;
-    if log {
-        con::writeMutPtr(p);
-        write(" ipc_call to ");
-        con::writeUInt(to);
-    }
-
+    let log = log::on(log::IPC) && to != 3;
     let handle = p.find_handle(to);
     match handle {
     Some(h) => {
         if log {
-            write("==> process ");
-            con::writeMutPtr(h.process());
-            con::newline();
+            kprintln!("{:p} ipc_call to {} ==> process {:p}", p, to, h.process());
         }
 
         p.set(process::InSend);
@@ -135,7 +202,7 @@ fn ipc_call(p : &mut Process, msg : u64, to : u64, arg1: u64, arg2: u64,
     None => abort("ipc_call: no recipient")
     }
     if log {
-        write("ipc_call: blocked\n");
+        kprintln!("ipc_call: blocked");
     }
 }
 
@@ -155,12 +222,8 @@ fn transfer_set_handle(target: &mut Process, source: &mut Process) {
                 if g.other != Some(h as *mut Handle) {
                     abort("other.other != self");
                 }
-                if log_transfer_message {
-                    write("transfer_set_handle: g=");
-                    con::writeMutPtr(g);
-                    write(", g.id()=");
-                    con::writeHex(rcpt);
-                    con::newline();
+                if log::on(log::TRANSFER_MESSAGE) {
+                    kprintln!("transfer_set_handle: g={:p}, g.id()={:#x}", g, rcpt);
                 }
             },
             // Associate handles now.
@@ -179,27 +242,24 @@ fn transfer_set_handle(target: &mut Process, source: &mut Process) {
         // TODO Assert that rcpt <-> from. (But the caller is responsible for
         // checking that first.)
     }
-    if log_transfer_message {
-        write("transfer_set_handle: rcpt=");
-        con::writeHex(rcpt);
-        write(" for ");
-        con::writeHex(target.regs().rdi);
-        write(" from ");
-        con::writeHex(from);
-        con::newline();
+    if log::on(log::TRANSFER_MESSAGE) {
+        kprintln!("transfer_set_handle: rcpt={:#x} for {:#x} from {:#x}", rcpt, target.regs().rdi, from);
     }
     target.regs().rdi = rcpt;
 }
 
+fn trace_received(target : &mut Process, from : u32) {
+    let id = target.id;
+    let r = target.regs();
+    kprintln!("trace [{}] received {:#x} on handle {:#x} from proc {} args {:#x} {:#x} {:#x} {:#x} {:#x}",
+        id, r.rax, r.rdi, from, r.rsi, r.rdx, r.r10, r.r8, r.r9);
+}
+
 fn transfer_message(target: &mut Process, source: &mut Process) -> ! {
     transfer_set_handle(target, source);
 
-    if log_transfer_message {
-        write("transfer_message ");
-        con::writeMutPtr(target);
-        write(" <- ");
-        con::writeMutPtr(source);
-        con::newline();
+    if log::on(log::TRANSFER_MESSAGE) {
+        kprintln!("transfer_message {:p} <- {:p}", target, source);
     }
 
     // FIXME Should use special fastret for message passing instead of
@@ -219,9 +279,15 @@ fn transfer_message(target: &mut Process, source: &mut Process) -> ! {
     target.unset(process::InRecv);
     target.unset(process::FastRet);
     source.unset(process::InSend);
+    trace!(source, "message {:#x} delivered to proc {}", source.regs().rax, target.id);
+    if target.is(process::Traced) {
+        trace_received(target, source.id);
+    }
 
     let c = cpu();
-    if false && log_transfer_message {
+    c.stats.count.transfers += 1;
+    c.events.record(events::kind::TRANSFER, source.id, target.id as u64, target.regs().rax);
+    if false && log::on(log::TRANSFER_MESSAGE) {
         dump_runqueue(&c.runqueue);
         target.dump();
         source.dump();
@@ -234,7 +300,7 @@ fn transfer_message(target: &mut Process, source: &mut Process) -> ! {
         c.queue(source);
     }
 
-    if false && log_transfer_message {
+    if false && log::on(log::TRANSFER_MESSAGE) {
         dump_runqueue(&c.runqueue);
         target.dump();
         source.dump();
@@ -263,6 +329,8 @@ pub fn can_deliver_pulse(p : &mut Process, rcpt: u64) -> bool {
 }
 
 fn deliver_pulse(p: &mut Process, rcpt: u64, pulses: u64) -> ! {
+    trace!(p, "pulses {:#x} on handle {:#x}", pulses, rcpt);
+    cpu().events.record(events::kind::PULSE, p.id, rcpt, pulses);
     p.regs().rdi = rcpt;
     p.regs().rsi = pulses;
     // See comment in transfer_message about special ipc-return
@@ -271,6 +339,32 @@ fn deliver_pulse(p: &mut Process, rcpt: u64, pulses: u64) -> ! {
     syscall_return(p, nr::PULSE);
 }
 
+// Tell debugger p that target stopped at a trap, like a message from the
+// kernel. See debug.rs.
+fn deliver_debug_stop(p : &mut Process, target : &mut Process) -> ! {
+    let rcpt = target.debugger_handle;
+    trace!(p, "proc {} stopped at trap {} on handle {:#x}", target.id, target.trap, rcpt);
+    target.unset(process::DebugStop);
+    p.regs().rdi = rcpt;
+    p.regs().rsi = target.trap as u64;
+    p.regs().rdx = target.rip;
+    p.unset(process::FastRet);
+    p.unset(process::InRecv);
+    syscall_return(p, nr::DEBUG);
+}
+
+// target (which has a debugger) has just stopped at a trap. Report it now if
+// the debugger is receiving, otherwise when it does.
+pub fn report_stop(target : &mut Process) -> ! {
+    let d = target.debugger().unwrap();
+    if can_deliver_pulse(d, target.debugger_handle) {
+        deliver_debug_stop(d, target);
+    }
+    target.set(process::DebugStop);
+    d.add_waiter(target);
+    unsafe { cpu().run(); }
+}
+
 fn send_or_block(sender : &mut Process, h : &mut Handle, msg: u64,
         arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) {
     // Save regs - either we'll copy these in transfer_message or we'll
@@ -303,26 +397,19 @@ fn send_or_block(sender : &mut Process, h : &mut Handle, msg: u64,
         }
     }
 
-    if log_ipc {
-        write("send_or_block: ");
-        con::writeMutPtr(sender);
-        write(" waits for ");
-        con::writeMutPtr(p);
-        con::newline();
+    if log::on(log::IPC) {
+        kprintln!("send_or_block: {:p} waits for {:p}", sender, p);
     }
+    trace!(sender, "blocks: proc {} is not receiving from us", p.id);
+    cpu().stats.count.blocks += 1;
     p.add_waiter(sender)
 }
 
 #[inline(never)]
 fn ipc_send(p : &mut Process, msg : u64, to : u64, arg1: u64, arg2: u64,
         arg3: u64, arg4: u64, arg5: u64) {
-    if log_ipc && to != 3 {
-        con::writeMutPtr(p);
-        write(" ipc_send to ");
-        con::writeHex(to);
-//        write(" ==>");
-//        con::writeMutPtr(p.find_handle(to).unwrap().process());
-        con::newline();
+    if log::on(log::IPC) && to != 3 {
+        kprintln!("{:p} ipc_send to {:#x}", p, to);
     }
 
     let handle = p.find_handle(to);
@@ -345,26 +432,18 @@ fn ipc_recv(p : &mut Process, from : u64) {
         handle = p.find_handle(from);
     }
 
-    if log_recv {
-        con::writeMutPtr(p);
-        write(" recv from ");
-        con::writeUInt(from);
-    }
-
     p.set(process::InRecv);
     p.regs().rdi = from;
     match handle {
         Some(h) => {
-            if log_recv {
-                write(" ==> process ");
-                con::writeMutPtr(h.process());
-                con::newline();
+            if log::on(log::RECV) {
+                kprintln!("{:p} recv from {} ==> process {:p}", p, from, h.process());
             }
             recv(p, h)
         },
         None => {
-            if log_recv && from != 0 {
-                write(" ==> fresh\n");
+            if log::on(log::RECV) {
+                kprintln!("{:p} recv from {}{}", p, from, if from != 0 { " ==> fresh" } else { "" });
             }
             recv_from_any(p, from)
         }
@@ -376,7 +455,11 @@ fn recv(p: &mut Process, handle: &mut Handle) {
     let rcpt = handle.process();
     if rcpt.is(process::InSend) && other_id == rcpt.regs().rdi {
         transfer_message(p, rcpt);
+    } else if rcpt.is(process::DebugStop) && rcpt.debugger_handle == handle.id() {
+        p.remove_waiter(rcpt);
+        deliver_debug_stop(p, rcpt);
     } else {
+        trace!(p, "blocks: waiting for proc {}", rcpt.id);
         rcpt.add_waiter(p);
     }
 }
@@ -384,7 +467,7 @@ fn recv(p: &mut Process, handle: &mut Handle) {
 fn recv_from_any(p : &mut Process, _id: u64) {
     let mut sender = None;
     for waiter in p.waiters.iter() {
-        if waiter.is(process::InSend) {
+        if waiter.is(process::InSend) || waiter.is(process::DebugStop) {
             sender = Some(waiter);
             break;
         }
@@ -392,6 +475,9 @@ fn recv_from_any(p : &mut Process, _id: u64) {
     match sender {
         Some(s) => {
             p.remove_waiter(s);
+            if s.is(process::DebugStop) {
+                deliver_debug_stop(p, s);
+            }
             transfer_message(p, s);
         },
         None => ()
@@ -409,10 +495,10 @@ fn recv_from_any(p : &mut Process, _id: u64) {
         deliver_pulse(p, 0, irqs);
     }
 
-    if log_recv {
-        con::writeMutPtr(p);
-        write(" recv: nothing to receive\n");
+    if log::on(log::RECV) {
+        kprintln!("{:p} recv: nothing to receive", p);
     }
+    trace!(p, "blocks: nothing to receive");
 
     // Nothing to receive, run something else.
     unsafe { c.run(); }
@@ -420,13 +506,8 @@ fn recv_from_any(p : &mut Process, _id: u64) {
 
 #[inline(never)]
 fn syscall_pulse(p: &mut Process, handle: u64, pulses: u64) -> ! {
-    if log_pulse {
-        con::writeMutPtr(p);
-        write(" send pulse ");
-        con::writeHex(pulses);
-        write(" to ");
-        con::writeHex(handle);
-        con::newline();
+    if log::on(log::PULSE) {
+        kprintln!("{:p} send pulse {:#x} to {:#x}", p, pulses, handle);
     }
 
     let maybe_h = p.find_handle(handle);
@@ -444,9 +525,8 @@ fn syscall_pulse(p: &mut Process, handle: u64, pulses: u64) -> ! {
         deliver_pulse(q, g.id(), pulses);
     }
     if g.add_pulses(pulses) == 0 {
-        if log_pulse {
-            con::writeMutPtr(q);
-            write(" can't receive pulse right now, pending\n");
+        if log::on(log::PULSE) {
+            kprintln!("{:p} can't receive pulse right now, pending", q);
         }
 
         // First pulse added to this process
@@ -470,18 +550,9 @@ fn syscall_map(p: &mut Process, handle: u64, mut prot: MapFlag, addr: u64, mut o
         }
     }
 
-    if log_map {
-        write("map: handle=");
-        con::writeHex(handle);
-        write(" prot=");
-        con::writeHex(prot);
-        write(" addr=");
-        con::writeHex(addr);
-        write(" size=");
-        con::writeHex(size);
-        write(" offset=");
-        con::writeHex(offset);
-        con::newline();
+    if log::on(log::MAP) {
+        kprintln!("map: handle={:#x} prot={:#x} addr={:#x} size={:#x} offset={:#x}",
+            handle, prot, addr, size, offset);
     }
 
     p.aspace().map_range(addr, addr + size, handle, (offset - addr) | (prot as u64));
@@ -499,22 +570,15 @@ fn syscall_pfault(p : &mut Process, mut vaddr: u64, access: MapFlag) {
     // set fault address
     p.fault_addr = vaddr;
     p.set(process::PFault);
+    cpu().stats.fault(stats::Fault::Pager);
     let prot = access & mapflag::RWX;
     // Look up vaddr, get handle, offset and flags
     let card = p.aspace().mapcard_find_def(vaddr);
     let offset = card.paddr(vaddr);
 
-    if log_pfault {
-        con::writeMutPtr(p);
-        write(" fault: vaddr=");
-        con::writeHex(vaddr);
-        write(" handle=");
-        con::writeHex(card.handle);
-        write(" offset=");
-        con::writeHex(offset);
-        write(" prot=");
-        con::writeHex(prot);
-        con::newline();
+    if log::on(log::PFAULT) {
+        kprintln!("{:p} fault: vaddr={:#x} handle={:#x} offset={:#x} prot={:#x}",
+            p, vaddr, card.handle, offset, prot);
     }
 
     // Now do the equivalent of sendrcv with rdi=handle, rsi=offset, rdx=flags
@@ -526,15 +590,8 @@ fn syscall_grant(p: &mut Process, id: u64, mut vaddr: u64, mut prot: MapFlag) {
     vaddr &= !0xfff;
     prot &= mapflag::RWX;
 
-    if log_grant {
-        con::writeMutPtr(p);
-        write(" grant: id=");
-        con::writeHex(id);
-        write(" vaddr=");
-        con::writeHex(vaddr);
-        write(" prot=");
-        con::writeHex(prot);
-        con::newline();
+    if log::on(log::GRANT) {
+        kprintln!("{:p} grant: id={:#x} vaddr={:#x} prot={:#x}", p, id, vaddr, prot);
     }
 
     let handle = p.find_handle(id).unwrap();
@@ -589,12 +646,8 @@ fn syscall_grant(p: &mut Process, id: u64, mut vaddr: u64, mut prot: MapFlag) {
 #[inline(never)]
 fn syscall_hmod(p : &mut Process, id: u64, rename: u64, copy: u64) {
     let handle = p.find_handle(id);
-    if log_hmod {
-        con::writeMutPtr(p);
-        write(" hmod: id="); con::writeHex(id);
-        write(" rename="); con::writeHex(rename);
-        write(" copy="); con::writeHex(copy);
-        con::newline();
+    if log::on(log::HMOD) {
+        kprintln!("{:p} hmod: id={:#x} rename={:#x} copy={:#x}", p, id, rename, copy);
     }
     match handle {
     None => (),
@@ -615,14 +668,6 @@ fn syscall_hmod(p : &mut Process, id: u64, rename: u64, copy: u64) {
 
 #[inline(never)]
 fn syscall_portio(p : &mut Process, port : u16, op : u8, data: u32) -> ! {
-    if log_portio {
-        con::writeMutPtr(p);
-        write(" portio: port="); con::writeHex(port & 0xffff);
-        write(" op="); con::writeHex(op);
-        if op & 0x10 != 0 {
-            write(" write "); con::writeHex(data);
-        }
-    }
     let mut res : u32 = 0;
     unsafe { match op {
     0x01 => asm!("inb %dx, %al" : "={al}"(res) : "{dx}"(port)),
@@ -633,16 +678,165 @@ fn syscall_portio(p : &mut Process, port : u16, op : u8, data: u32) -> ! {
     0x14 => asm!("outl %eax, %dx" :: "{eax}"(data), "{dx}"(port)),
     _ => abort("unhandled portio operation")
     } }
-    if log_portio {
-        if op & 0x10 == 0 {
-            write(" res="); con::writeHex(res);
+    if log::on(log::PORTIO) {
+        if op & 0x10 != 0 {
+            kprintln!("{:p} portio: port={:#x} op={:#x} write {:#x}", p, port, op, data);
+        } else {
+            kprintln!("{:p} portio: port={:#x} op={:#x} res={:#x}", p, port, op, res);
         }
-        con::newline();
     }
     syscall_return(p, res as u64);
 }
 
+// Set the log categories in set and clear the ones in clear (see log.rs).
+// Returns the previous mask, so set = clear = 0 just reads it.
+#[inline(never)]
+fn syscall_logctl(p : &mut Process, set : u64, clear : u64) -> ! {
+    if !p.is(process::Privileged) {
+        syscall_return(p, err::EPERM);
+    }
+    let old = log::get();
+    log::set((old & !clear) | set);
+    syscall_return(p, old);
+}
+
+// Turn tracing on (enable != 0) or off for the process at the other end of
+// handle id, or for the caller itself with id 0. Only for privileged processes
+// and those with the Inspect capability. Returns whether it was traced before,
+// EPERM, or EINVAL for a bad handle.
+fn syscall_trace(p : &mut Process, id : u64, enable : u64) -> ! {
+    if !p.is(process::Privileged) && !p.is(process::Inspect) {
+        syscall_return(p, err::EPERM);
+    }
+    let target = if id == 0 {
+        p as *mut Process
+    } else {
+        match p.find_handle(id) {
+            Some(h) => h.process() as *mut Process,
+            _ => syscall_return(p, err::EINVAL),
+        }
+    };
+    let target = unsafe { &mut *target };
+    let old = target.is(process::Traced);
+    if enable != 0 {
+        target.set(process::Traced);
+    } else {
+        target.unset(process::Traced);
+    }
+    syscall_return(p, old as u64);
+}
+
+// Start a program from the initrd. offset and size give the file data in the
+// archive, and args_len bytes at args are its arguments (at most
+// max_args_len). The caller gets handle id to the new process, which sees the
+// caller as handle 1. Returns id.
+fn syscall_newproc(p : &mut Process, offset : u64, size : u64, id : u64, args : u64, args_len : u64) -> ! {
+    if !p.is(process::Privileged) {
+        syscall_return(p, err::EPERM);
+    }
+    let archive = match initrd::get() {
+        Some(a) if a.contains(offset, size) => a,
+        _ => syscall_return(p, err::EINVAL),
+    };
+    if id == 0 || p.find_handle(id).is_some() || args_len > max_args_len as u64 {
+        syscall_return(p, err::EINVAL);
+    }
+    // Too big for the kernel stack
+    let buf : *mut u8 = malloc(max_args_len);
+    let args_buf = unsafe { slice::from_raw_parts_mut(buf, args_len as usize) };
+    if !p.aspace().copy_from_user(args, args_buf) {
+        free(buf);
+        syscall_return(p, err::EFAULT);
+    }
+    let q = new_proc(Image::Phys(archive.paddr + offset, size), args_buf, false);
+    free(buf);
+    if q.is_null() {
+        syscall_return(p, err::EINVAL);
+    }
+    let q = unsafe { &mut *q };
+    p.assoc_handles(id, q, 1);
+    p.find_handle(id).unwrap().debug = true;
+    cpu().queue(q);
+    syscall_return(p, id);
+}
+
+// Map the boot info page read-only at addr. Returns addr.
+fn syscall_bootinfo(p : &mut Process, addr : u64) -> ! {
+    if !bootinfo::map(p.aspace(), addr) {
+        syscall_return(p, err::EINVAL);
+    }
+    syscall_return(p, addr);
+}
+
+// Write the len bytes at addr to the console. Returns the number of bytes
+// written, or EFAULT.
+fn syscall_writebuf(p : &mut Process, addr : u64, len : u64) -> ! {
+    let mut buf = [0u8; 128];
+    let mut done = 0;
+    while done < len {
+        let n = min(len - done, buf.len() as u64) as usize;
+        if !p.aspace().copy_from_user(addr.wrapping_add(done), &mut buf[..n]) {
+            syscall_return(p, err::EFAULT);
+        }
+        con::writeBytes(&buf[..n]);
+        done += n as u64;
+    }
+    syscall_return(p, len);
+}
+
+// Kernel log operations, for privileged processes:
+// KLOG_MAP: map the log read-only at arg (page aligned). Returns its size.
+// KLOG_WAIT: block until there's a record with sequence number arg or later.
+// Returns the sequence number of the next record.
+fn syscall_klog(p : &mut Process, op : u64, arg : u64) -> ! {
+    if !p.is(process::Privileged) {
+        syscall_return(p, err::EPERM);
+    }
+    match op {
+        klog_op::MAP => match klog::map(p.aspace(), arg) {
+            0 => syscall_return(p, err::EINVAL),
+            size => syscall_return(p, size),
+        },
+        klog_op::WAIT => {
+            let next = klog::next_seq();
+            if arg < next {
+                syscall_return(p, next);
+            }
+            if !klog::wait(p) {
+                syscall_return(p, err::EBUSY);
+            }
+            unsafe { cpu().run(); }
+        },
+        _ => syscall_return(p, err::EINVAL),
+    }
+}
+
+// Introspection, for processes with the Inspect capability. See info.rs for
+// the ops and record layouts. Returns the total number of records, of which
+// those after the first start are copied to the len bytes at buf.
+fn syscall_info(p : &mut Process, op : u64, id : u64, start : u64, buf : u64, len : u64) -> ! {
+    if !p.is(process::Inspect) {
+        syscall_return(p, err::EPERM);
+    }
+    if op == info::op::GRANT {
+        match p.find_handle(id) {
+            Some(h) => h.process().set(process::Inspect),
+            None => syscall_return(p, err::EINVAL),
+        }
+        syscall_return(p, 0);
+    }
+    let res = info::info(p, op, id, start, buf, len);
+    syscall_return(p, res);
+}
+
+fn syscall_debug(p : &mut Process, op : u64, id : u64, arg : u64, buf : u64, len : u64) -> ! {
+    let res = debug::debug(p, op, id, arg, buf, len);
+    syscall_return(p, res);
+}
+
 #[inline(never)]
 fn syscall_return(p : &mut Process, res : u64) -> ! {
+    trace!(p, "= {:#x}", res);
+    cpu().events.record(events::kind::SYSRET, p.id, res, 0);
     cpu().syscall_return(p, res);
 }
@@ -0,0 +1,191 @@
+// Boot-time IPC topology: which processes get handles to each other, and with
+// what handle ids. It is declared in the module arguments:
+//
+//   module (cd)/fs.elf fs irq
+//   module (cd)/console.elf console
+//   module (cd)/app.elf app connect=fs:2,console:3:1
+//
+// The first word of the arguments names the module's role. connect=<role>:<id>
+// gives the process handle <id> to the process with that role. The id on the
+// other side is taken from a third field, from a matching connect= in the other
+// module's arguments, or else is the same as <id>. "irq" marks the process
+// that receives IRQs.
+//
+// If no module declares any connections, every process is connected to every
+// other process, using the module's position (starting at 1) as handle id.
+use cmdline::key_value;
+use cmdline::parse_uint;
+use cmdline::split;
+use cmdline::words;
+use con::Bytes;
+use log;
+use boot::max_modules;
+use process::Process;
+
+#[derive(Clone, Copy)]
+struct Node {
+    name : &'static [u8],
+    args : &'static [u8],
+    process : *mut Process,
+}
+
+const empty_node : Node = Node { name : &[], args : &[], process : 0 as *mut Process };
+
+struct Connect {
+    role : &'static [u8],
+    local : u64,
+    remote : Option<u64>,
+}
+
+pub struct Topology {
+    nodes : [Node; max_modules],
+    count : usize,
+}
+
+fn bad_connect(name : &[u8], what : &str, spec : &[u8]) {
+    kwarn!("{}: {} \"{}\"", Bytes(name), what, Bytes(spec));
+}
+
+fn parse_connect(spec : &'static [u8]) -> Option<Connect> {
+    let mut parts = split(spec, b':');
+    let role = match parts.next() {
+        Some(role) => role,
+        None => return None,
+    };
+    let local = match parts.next().and_then(parse_uint) {
+        Some(id) if id != 0 => id,
+        _ => return None,
+    };
+    let remote = match parts.next() {
+        Some(s) => match parse_uint(s) {
+            Some(id) if id != 0 => Some(id),
+            _ => return None,
+        },
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Connect { role : role, local : local, remote : remote })
+}
+
+// Call f for each valid connect= entry in args, and complain about the invalid
+// ones if report is set. The first word (the role name) is skipped.
+fn connections<F : FnMut(Connect)>(name : &[u8], args : &'static [u8], report : bool, mut f : F) {
+    for word in words(args).skip(1) {
+        let (key, value) = key_value(word);
+        if key != b"connect" {
+            continue;
+        }
+        for spec in split(value, b',') {
+            match parse_connect(spec) {
+                Some(c) => f(c),
+                None if report => bad_connect(name, "bad connection", spec),
+                None => (),
+            }
+        }
+    }
+}
+
+impl Topology {
+    pub fn new() -> Topology {
+        Topology { nodes : [empty_node; max_modules], count : 0 }
+    }
+
+    // When full, p still runs but doesn't get connected to anything.
+    pub fn add(&mut self, name : &'static [u8], args : &'static [u8], p : *mut Process) {
+        if self.count == max_modules {
+            kwarn!("topology: too many processes, not connecting \"{}\"", Bytes(name));
+            return;
+        }
+        self.nodes[self.count] = Node { name : name, args : args, process : p };
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn process<'a>(&self, i : usize) -> &'a mut Process {
+        unsafe { &mut *self.nodes[i].process }
+    }
+
+    pub fn find(&self, name : &[u8]) -> Option<usize> {
+        (0..self.count).find(|&i| self.nodes[i].name == name)
+    }
+
+    // The process marked with "irq" in its arguments.
+    pub fn irq_node(&self) -> Option<usize> {
+        (0..self.count).find(|&i| words(self.nodes[i].args).skip(1).any(|w| w == b"irq"))
+    }
+
+    fn declared(&self) -> bool {
+        (0..self.count).any(|i| words(self.nodes[i].args).skip(1)
+            .any(|w| key_value(w).0 == b"connect"))
+    }
+
+    // The id node i declared for its connection to role, if any.
+    fn declared_id(&self, i : usize, role : &[u8]) -> Option<u64> {
+        let mut res = None;
+        connections(self.nodes[i].name, self.nodes[i].args, false, |c| {
+            if c.role == role {
+                res = Some(c.local);
+            }
+        });
+        res
+    }
+
+    fn assoc(&self, i : usize, id : u64, j : usize, other_id : u64) {
+        if log::on(log::ASSOC_PROCS) {
+            kprintln!("{}:{} <-> {}:{}", Bytes(self.nodes[i].name), id, other_id, Bytes(self.nodes[j].name));
+        }
+        let p = self.process(i);
+        let q = self.process(j);
+        if p.find_handle(id).is_some() || q.find_handle(other_id).is_some() {
+            kwarn!("topology: handle id collision, not connecting {}:{} <-> {}:{}",
+                Bytes(self.nodes[i].name), id, other_id, Bytes(self.nodes[j].name));
+            return;
+        }
+        p.assoc_handles(id, q, other_id);
+    }
+
+    fn connect_all(&self) {
+        for i in 0..self.count {
+            for j in i + 1..self.count {
+                self.assoc(i, (j + 1) as u64, j, (i + 1) as u64);
+            }
+        }
+    }
+
+    // Create the handles declared by all processes.
+    pub fn connect(&self) {
+        if !self.declared() {
+            self.connect_all();
+            return;
+        }
+        for i in 0..self.count {
+            let name = self.nodes[i].name;
+            connections(name, self.nodes[i].args, true, |c| {
+                let j = match self.find(c.role) {
+                    Some(j) if j != i => j,
+                    _ => {
+                        bad_connect(name, "no such role", c.role);
+                        return;
+                    }
+                };
+                let remote = match self.declared_id(j, name) {
+                    // Mutual declaration, already connected from the other side
+                    Some(_) if j < i => return,
+                    Some(id) => {
+                        if c.remote.map_or(false, |r| r != id) {
+                            bad_connect(name, "conflicting ids for", c.role);
+                        }
+                        id
+                    },
+                    None => c.remote.unwrap_or(c.local),
+                };
+                self.assoc(i, c.local, j, remote);
+            });
+        }
+    }
+}
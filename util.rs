@@ -1,10 +1,15 @@
+use core::slice;
+
 use start32::kernel_base;
 use con::Console;
 use con::Writer;
 
 #[link_name="abort"] #[allow(dead_code)]
 pub extern "C" fn abort2(msg: &'static str) -> ! {
-    let mut con = Console::new((kernel_base + 0xb80a0) as *mut u16);
+    kerror!("abort: {}", msg);
+    ::crash::dump_here();
+    let (width, height) = (::con::get().width(), ::con::get().height());
+    let mut con = Console::new((kernel_base + 0xb80a0) as *mut u16, width, height - 1);
     con.color = 0x4f00;
     con.write(msg);
     con.newline();
@@ -31,6 +36,21 @@ pub extern "C" fn rust_fail_bounds_check() -> ! {
     abort("rust_fail_bounds_check");
 }
 
+pub fn strlen(c_str : *const u8) -> usize {
+    let mut len = 0;
+    unsafe {
+        while *c_str.offset(len as isize) != 0 {
+            len += 1;
+        }
+    }
+    len
+}
+
+// The bytes of a NUL-terminated string, not including the NUL.
+pub fn cstr(c_str : *const u8) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(c_str, strlen(c_str)) }
+}
+
 pub fn concat<U, T : Concat<U>>(h: T, l : T) -> U {
     h.concat(l)
 }
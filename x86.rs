@@ -12,12 +12,67 @@ pub unsafe fn ltr(tr : u16) {
     asm!("ltr %ax" :: "{ax}"(tr));
 }
 
+pub unsafe fn inb(port : u16) -> u8 {
+    let res : u8;
+    asm!("inb %dx, %al" : "={al}"(res) : "{dx}"(port) :: "volatile");
+    res
+}
+pub unsafe fn outb(port : u16, data : u8) {
+    asm!("outb %al, %dx" :: "{al}"(data), "{dx}"(port) :: "volatile");
+}
+
+pub fn rdtsc() -> u64 {
+    let (low, high) : (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile"); }
+    (high as u64) << 32 | low as u64
+}
+
+// The caller's frame pointer.
+#[inline(always)]
+pub fn rbp() -> u64 {
+    let rbp : u64;
+    unsafe { asm!("movq %rbp, $0" : "=r"(rbp)); }
+    rbp
+}
+
 pub fn cr2() -> u64 {
     let mut cr2 : u64;
     unsafe { asm!("mov %cr2, $0": "=r" (cr2)); }
     return cr2;
 }
 
+pub fn cr0() -> u64 {
+    let cr0 : u64;
+    unsafe { asm!("mov %cr0, $0": "=r" (cr0)); }
+    cr0
+}
+
+pub fn cr3() -> u64 {
+    let cr3 : u64;
+    unsafe { asm!("mov %cr3, $0": "=r" (cr3)); }
+    cr3
+}
+
+pub fn cr4() -> u64 {
+    let cr4 : u64;
+    unsafe { asm!("mov %cr4, $0": "=r" (cr4)); }
+    cr4
+}
+
+#[inline(always)]
+pub fn rsp() -> u64 {
+    let rsp : u64;
+    unsafe { asm!("movq %rsp, $0" : "=r"(rsp)); }
+    rsp
+}
+
+#[inline(always)]
+pub fn get_rflags() -> u64 {
+    let rflags : u64;
+    unsafe { asm!("pushfq; popq $0" : "=r"(rflags)); }
+    rflags
+}
+
 pub mod seg {
     #![allow(dead_code)]
     pub const code32 : u16 = 8;
@@ -42,14 +97,25 @@ static GatePresent : u8 = 0x80;
 static GateTypeInterrupt : u8 = 0x0e;
 
 pub fn entry(handler_ptr : *const u8) -> Entry {
+    entry_dpl(handler_ptr, 0)
+}
+
+// An entry that code at privilege level dpl and up can use with int n.
+pub fn entry_dpl(handler_ptr : *const u8, dpl : u8) -> Entry {
     let handler = handler_ptr as u64;
     let low = concat(handler as u16, seg::code);
-    let flags = (GatePresent | GateTypeInterrupt) as u16;
+    let flags = (GatePresent | GateTypeInterrupt | dpl << 5) as u16;
     let high = concat((handler >> 16) as u16, flags << 8);
 
     (concat(high, low), handler as u64 >> 32)
 }
 
+// An entry that switches to stack ist from the TSS.
+pub fn entry_ist(handler_ptr : *const u8, ist : u8) -> Entry {
+    let (low, high) = entry(handler_ptr);
+    (low | (ist as u64) << 32, high)
+}
+
 pub const null_entry : Entry = (0,0);
 
 pub type Entry = (u64,u64);
@@ -78,19 +144,56 @@ pub unsafe fn load(table: *const [Entry; 49]) {
     lidt(&idtr);
 }
 
+// The kernel's registers at a debug trap or fatal exception, as pushed by the
+// trap handler in syscall.asm.
+#[repr(C)]
+pub struct KernelFrame {
+    pub r15 : u64,
+    pub r14 : u64,
+    pub r13 : u64,
+    pub r12 : u64,
+    pub r11 : u64,
+    pub r10 : u64,
+    pub r9 : u64,
+    pub r8 : u64,
+    pub rbp : u64,
+    pub rdx : u64,
+    pub rcx : u64,
+    pub rbx : u64,
+    pub rax : u64,
+    pub rdi : u64,
+    pub rsi : u64,
+    pub vector : u64,
+    pub rip : u64,
+    pub cs : u64,
+    pub rflags : u64,
+    pub rsp : u64,
+    pub ss : u64,
+}
+
 #[no_mangle]
 pub fn irq_entry(vec : u8, err : u64) -> ! {
     use page_fault;
     use handler_NM;
     use generic_irq_handler;
     use cpu;
+    use crash;
+    use gdb;
+    use util::abort;
     cpu().leave_proc();
     let p = cpu().get_process();
-    if vec == 7 {
+    if vec == 1 || vec == 3 {
+        gdb::user_trap(p.unwrap(), vec);
+    } else if vec == 7 {
         handler_NM();
     } else if vec == 14 {
         page_fault(p.unwrap(), err);
-    } else if vec >= 32 {
+    } else if vec < 32 {
+        let p = p.unwrap();
+        kerror!("exception {} {} error={:#x} at {:#x} in process {}", vec,
+            crash::exception_name(vec as u64), err, p.rip, p.id);
+        abort("Unhandled exception");
+    } else {
         match p {
             Some(p) => cpu().queue(p),
             None => (),
@@ -102,14 +205,19 @@ pub fn irq_entry(vec : u8, err : u64) -> ! {
 
 pub unsafe fn init() {
     extern {
-        fn handler_PF_stub();
-        fn handler_NM_stub();
+        static exc_handlers : [u64; 32];
         // We can generate this, probably in less than 68 bytes?
         static irq_handlers : [u32; 17];
     }
     static mut idt_table : [Entry; 49] = [null_entry; 49];
-    idt_table[7] = entry(handler_NM_stub as *const u8);
-    idt_table[14] = entry(handler_PF_stub as *const u8);
+    for i in 0..32 {
+        idt_table[i] = entry(exc_handlers[i] as *const u8);
+    }
+    // int3 is allowed in user mode
+    idt_table[3] = entry_dpl(exc_handlers[3] as *const u8, 3);
+    // Double faults get a stack of their own (IST1, see start32.asm), the
+    // kernel stack might be what caused them.
+    idt_table[8] = entry_ist(exc_handlers[8] as *const u8, 1);
     for i in 32..48 {
         idt_table[i] = entry((&irq_handlers[i - 32]) as *const u32 as *const u8);
     }
@@ -152,6 +260,7 @@ pub mod msr {
 }
 
 pub mod rflags {
+    pub static TF : u64 = 1 << 8;
     pub static IF : u64 = 1 << 9;
     pub static VM : u64 = 1 << 17;
 }
@@ -161,6 +270,10 @@ pub mod efer {
     pub static NXE : u64 = 1 << 11;
 }
 
+pub unsafe fn invlpg(vaddr : u64) {
+    asm!("invlpg ($0)" :: "r"(vaddr) : "memory");
+}
+
 pub unsafe fn set_cr3(cr3 : u64) {
     let mut old_cr3 : u64;
     asm!("movq %cr3, $0" : "=r"(old_cr3));